@@ -0,0 +1,383 @@
+use std::{cmp::Ordering, collections::BinaryHeap, path, time::UNIX_EPOCH};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, DefaultBodyLimit, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    config::Config, meta::Sidecar, silly::Slonkable, store_upload, walk_files, AppError, ArcState,
+    PeerAddr, Stats, Stored, UploadLength, DELETE_TOKEN_HEADER,
+};
+
+#[derive(Debug, Serialize)]
+pub struct FileMeta {
+    pub name: String,
+    pub url: String,
+    pub size: u64,
+    pub modified: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_name: Option<String>,
+}
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SortBy {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct ListQuery {
+    pub sort: SortBy,
+    pub order: Order,
+    pub limit: usize,
+    /// `next` from the previous page.
+    pub cursor: Option<String>,
+}
+
+impl Default for ListQuery {
+    fn default() -> Self {
+        Self {
+            sort: Default::default(),
+            order: Default::default(),
+            limit: DEFAULT_PAGE_SIZE,
+            cursor: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FileList {
+    pub files: Vec<FileMeta>,
+    /// Cursor for the page after this one, `None` on the last page.
+    pub next: Option<String>,
+}
+
+/// What files are sorted by, the name breaks ties so every file has its own
+/// place and pages stay stable while files are uploaded.
+type SortKey = (u64, String);
+
+/// A file in the page being collected, ordered as the page is.
+struct Listed {
+    key: SortKey,
+    order: Order,
+    meta: FileMeta,
+}
+
+impl PartialEq for Listed {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Listed {}
+
+impl PartialOrd for Listed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Listed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.order {
+            Order::Asc => self.key.cmp(&other.key),
+            Order::Desc => other.key.cmp(&self.key),
+        }
+    }
+}
+
+fn sort_key(sort: SortBy, meta: &FileMeta) -> SortKey {
+    let value = match sort {
+        SortBy::Name => 0,
+        SortBy::Size => meta.size,
+        SortBy::Modified => meta.modified.unwrap_or(0),
+    };
+    (value, meta.name.clone())
+}
+
+fn encode_cursor((value, name): &SortKey) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}/{}", value, name))
+}
+
+fn decode_cursor(cursor: &str) -> Option<SortKey> {
+    let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+    let (value, name) = decoded.split_once('/')?;
+    Some((value.parse().ok()?, name.to_string()))
+}
+
+#[derive(Debug, Serialize)]
+struct Uploaded {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonUpload {
+    pub name: String,
+    /// Base64 encoded file contents.
+    pub content: String,
+}
+
+/// Errors as `{"error": "..."}` with the status the human facing routes use.
+pub struct ApiError(AppError);
+
+impl From<AppError> for ApiError {
+    fn from(value: AppError) -> Self {
+        Self(value)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        error!("{}", self.0);
+        let error = self.0.to_string();
+        let status = self.0.status();
+        (
+            status,
+            Slonkable::from(serde_json::json!({ "error": error })),
+        )
+            .into_response()
+    }
+}
+
+/// JSON API served under `/api/v1`, for integrations that shouldn't have to
+/// deal with the human facing routes.
+pub fn router(config: &Config) -> Router<ArcState> {
+    // base64 takes 4 bytes for every 3, plus some room for the rest of the JSON
+    let json_upload_limit = (config.max_file_size / 3 * 4 + 4096)
+        .try_into()
+        .unwrap_or(usize::MAX);
+
+    Router::new()
+        .route("/stats", get(stats))
+        .route("/files", get(list).post(upload_json))
+        .route("/files/:file", get(meta).put(upload_raw))
+        .layer(DefaultBodyLimit::max(json_upload_limit))
+}
+
+async fn stats(State(state): State<ArcState>) -> Slonkable<Stats> {
+    state.stats().clone().into()
+}
+
+/// Lists files a page at a time. Only the page being collected (plus one, to
+/// know if there is a next) is kept in memory, however many files there are.
+/// Admin only, the names it gives away are what the random prefix hides.
+async fn list(
+    State(state): State<ArcState>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> Result<Slonkable<FileList>, ApiError> {
+    state.authorize_admin(&headers, &peer).await?;
+
+    let limit = query.limit.clamp(1, MAX_PAGE_SIZE);
+    let after = match &query.cursor {
+        Some(cursor) => Some(decode_cursor(cursor).ok_or(AppError::BadRequest)?),
+        None => None,
+    };
+
+    // walking a big upload_dir takes a while, so it is kept off the workers
+    let page = tokio::task::spawn_blocking(move || {
+        // the top of the heap is the file that would come last in the page
+        let mut page = BinaryHeap::with_capacity(limit + 1);
+        walk_files(
+            path::Path::new(&state.config.upload_dir),
+            state.config.shard,
+            &mut |file, metadata| {
+                let name = file.file_name().to_string_lossy().into_owned();
+                let meta = FileMeta {
+                    url: state.file_url(&name),
+                    name,
+                    size: metadata.len(),
+                    modified: modified_secs(metadata),
+                    original_name: None,
+                };
+                let listed = Listed {
+                    key: sort_key(query.sort, &meta),
+                    order: query.order,
+                    meta,
+                };
+
+                let on_earlier_page = after.as_ref().is_some_and(|after| match query.order {
+                    Order::Asc => listed.key <= *after,
+                    Order::Desc => listed.key >= *after,
+                });
+                if on_earlier_page {
+                    return;
+                }
+
+                page.push(listed);
+                if page.len() > limit + 1 {
+                    page.pop();
+                }
+            },
+        )
+        .map(|()| page)
+    })
+    .await
+    .map_err(|err| AppError::from(std::io::Error::from(err)))?
+    .map_err(AppError::from)?;
+
+    let mut page = page.into_sorted_vec();
+    let next = if page.len() > limit {
+        page.truncate(limit);
+        page.last().map(|listed| encode_cursor(&listed.key))
+    } else {
+        None
+    };
+
+    Ok(FileList {
+        files: page.into_iter().map(|listed| listed.meta).collect(),
+        next,
+    }
+    .into())
+}
+
+async fn meta(
+    State(state): State<ArcState>,
+    Path(file_name): Path<String>,
+) -> Result<Slonkable<FileMeta>, ApiError> {
+    if file_name.contains('/') || file_name == "." || file_name == ".." {
+        return Err(AppError::NotFound.into());
+    }
+
+    let metadata = match tokio::fs::metadata(state.config.file_path(&file_name)).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Err(AppError::NotFound.into()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(AppError::NotFound.into())
+        }
+        Err(err) => return Err(AppError::from(err).into()),
+    };
+
+    let original_name = Sidecar::load(&state.config, &state.config.normalize_name(&file_name))
+        .await
+        .map_err(AppError::from)?
+        .and_then(|sidecar| sidecar.original_name);
+
+    Ok(FileMeta {
+        url: state.file_url(&file_name),
+        name: file_name,
+        size: metadata.len(),
+        modified: modified_secs(&metadata),
+        original_name,
+    }
+    .into())
+}
+
+async fn upload_raw(
+    State(state): State<ArcState>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, ApiError> {
+    let stored = store_upload(
+        &state,
+        &peer,
+        path,
+        &headers,
+        UploadLength::Declared,
+        body.into_data_stream(),
+    )
+    .await?;
+    Ok(uploaded(&state, stored))
+}
+
+async fn upload_json(
+    State(state): State<ArcState>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    mut headers: HeaderMap,
+    Json(upload): Json<JsonUpload>,
+) -> Result<Response, ApiError> {
+    let content = STANDARD
+        .decode(upload.content)
+        .map_err(|_| AppError::BadRequest)?;
+
+    // the headers describe the JSON document, not the file inside it
+    headers.remove("content-encoding");
+    headers.insert("content-length", content.len().into());
+
+    let stored = store_upload(
+        &state,
+        &peer,
+        upload.name,
+        &headers,
+        UploadLength::Declared,
+        Body::from(content).into_data_stream(),
+    )
+    .await?;
+    Ok(uploaded(&state, stored))
+}
+
+fn uploaded(state: &ArcState, stored: Stored) -> Response {
+    (
+        StatusCode::CREATED,
+        stored
+            .delete_token
+            .map(|delete_token| [(DELETE_TOKEN_HEADER, delete_token)]),
+        Slonkable::from(Uploaded {
+            url: state.file_url(&stored.file_name),
+            name: stored.file_name,
+        }),
+    )
+        .into_response()
+}
+
+fn modified_secs(metadata: &std::fs::Metadata) -> Option<u64> {
+    metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_cursors() {
+        for key in [
+            (0, "a.txt".to_string()),
+            (1234, "name/with/slashes".to_string()),
+            (u64::MAX, String::new()),
+        ] {
+            assert_eq!(decode_cursor(&encode_cursor(&key)), Some(key));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_cursors() {
+        assert_eq!(decode_cursor("not base64!"), None);
+        assert_eq!(decode_cursor(&URL_SAFE_NO_PAD.encode("no-separator")), None);
+        assert_eq!(decode_cursor(&URL_SAFE_NO_PAD.encode("-1/a.txt")), None);
+    }
+}
@@ -0,0 +1,73 @@
+use std::{collections::HashMap, fmt::Debug, net::IpAddr};
+
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use thiserror::Error;
+
+use crate::silly::{constant_time_eq, get_bearer_token};
+
+/// Who a request was authenticated as.
+#[derive(Debug, Clone, Default)]
+pub struct Identity {
+    /// `None` for anonymous requests.
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Missing credentials")]
+    Missing,
+    #[error("Invalid credentials")]
+    Invalid,
+}
+
+/// Decides whether a request may write, i.e. upload, reserve or cancel.
+/// Implement this to plug in your own scheme instead of static tokens.
+#[async_trait]
+pub trait Authenticator: Debug + Send + Sync {
+    async fn authenticate(&self, headers: &HeaderMap, ip: IpAddr) -> Result<Identity, AuthError>;
+}
+
+/// Lets everyone write, used when no upload tokens are configured.
+#[derive(Debug)]
+pub struct Anonymous;
+
+#[async_trait]
+impl Authenticator for Anonymous {
+    async fn authenticate(&self, _: &HeaderMap, _: IpAddr) -> Result<Identity, AuthError> {
+        Ok(Identity::default())
+    }
+}
+
+/// Bearer tokens from the `upload_tokens` table, keyed by the name they
+/// identify.
+#[derive(Debug)]
+pub struct StaticTokens {
+    tokens: HashMap<String, String>,
+}
+
+impl StaticTokens {
+    pub fn new(tokens: HashMap<String, String>) -> Self {
+        Self { tokens }
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticTokens {
+    async fn authenticate(&self, headers: &HeaderMap, _: IpAddr) -> Result<Identity, AuthError> {
+        let token = get_bearer_token(headers).ok_or(AuthError::Missing)?;
+
+        // every token is compared so the timing doesn't tell which one was close
+        let mut identity = None;
+        for (name, expected) in &self.tokens {
+            if constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+                identity = Some(name.clone());
+            }
+        }
+
+        match identity {
+            Some(name) => Ok(Identity { name: Some(name) }),
+            None => Err(AuthError::Invalid),
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     env, fs,
     path::{Path, PathBuf},
 };
 
-use anyhow::Result;
+use axum::http::Method;
 use serde::Deserialize;
 use thiserror::Error;
 use tokio::{fs::OpenOptions, io::AsyncReadExt};
@@ -15,7 +17,13 @@ pub struct HttpConfig {
     pub host: String,
     pub port: u16,
     pub concurrency_limit: usize,
+    pub shed_load: bool,
+    pub shed_retry_after: u64,
     pub behind_proxy: bool,
+    pub unix_socket: Option<String>,
+    pub accept_rate: u32,
+    pub accept_burst: u32,
+    pub proxy_protocol: bool,
 }
 
 impl Default for HttpConfig {
@@ -24,7 +32,13 @@ impl Default for HttpConfig {
             host: "0.0.0.0".to_string(),
             port: 4040,
             concurrency_limit: 512,
+            shed_load: false,
+            shed_retry_after: 5,
             behind_proxy: false,
+            unix_socket: None,
+            accept_rate: 0,
+            accept_burst: 64,
+            proxy_protocol: false,
         }
     }
 }
@@ -36,12 +50,22 @@ pub enum FileEnum {
     Path(String),
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NamePolicy {
+    #[default]
+    Posix,
+    Windows,
+    Strict,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[serde(default)]
 pub struct LoggingConfig {
     pub level: String,
     pub stderr: bool,
     pub file: FileEnum,
+    pub sample_rate: f64,
 }
 
 impl Default for LoggingConfig {
@@ -50,10 +74,27 @@ impl Default for LoggingConfig {
             level: "info".to_string(),
             stderr: true,
             file: FileEnum::Boolean(false),
+            sample_rate: 1.0,
         }
     }
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlCharPolicy {
+    #[default]
+    Reject,
+    Strip,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyNamePolicy {
+    #[default]
+    Reject,
+    PrefixOnly,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[serde(default)]
 pub struct Config {
@@ -61,11 +102,58 @@ pub struct Config {
     pub logging: LoggingConfig,
     pub upload_dir: String,
     pub temp_dir: String,
+    pub mirror_dir: Option<String>,
+    pub mirror_strict: bool,
     pub prefix_length: usize,
+    pub prefix_format: String,
     pub max_file_size: u64,
     pub max_file_name_length: usize,
     pub stats_interval: u64,
+    pub full_refresh_interval: u64,
     pub allocate: bool,
+    pub favicon: Option<String>,
+    pub max_open_files: usize,
+    pub preload: HashMap<String, Vec<String>>,
+    pub per_ip_daily_bytes: u64,
+    pub name_policy: NamePolicy,
+    pub control_chars: ControlCharPolicy,
+    pub empty_name: EmptyNamePolicy,
+    pub admin_token: Option<String>,
+    pub public_url: Option<String>,
+    pub created_response: bool,
+    pub max_decompression_ratio: u64,
+    pub sync_on_upload: bool,
+    pub reservation_ttl: u64,
+    pub shard: bool,
+    pub shard_length: usize,
+    pub strong_etags: bool,
+    pub require_content_length: bool,
+    pub allowed_methods: Vec<String>,
+    pub upload_tokens: HashMap<String, String>,
+    pub storage_full_hook: Vec<String>,
+    pub resume_free_space: u64,
+    pub metrics: bool,
+    pub store_original_names: bool,
+    pub max_files: u64,
+    pub security_headers: HashMap<String, String>,
+    pub maintenance: bool,
+    pub maintenance_page: Option<String>,
+    pub maintenance_retry_after: u64,
+    pub case_insensitive_names: bool,
+    pub store_checksums: bool,
+    pub reject_double_extension: bool,
+    pub benign_double_extensions: Vec<String>,
+    pub auth_failure_threshold: u32,
+    pub auth_failure_delay: u64,
+    pub max_json_response_size: usize,
+    pub allowed_extensions: Option<Vec<String>>,
+    pub blocked_extensions: Vec<String>,
+    pub file_ttl_secs: Option<u64>,
+    pub reap_interval: u64,
+    pub mime_sniffing: bool,
+    pub blocked_mime: Vec<String>,
+    pub min_upload_rate: u64,
+    pub min_upload_rate_grace: u64,
 }
 
 impl Default for Config {
@@ -73,17 +161,177 @@ impl Default for Config {
         Self {
             upload_dir: "files".to_string(),
             temp_dir: "temp".to_string(),
+            mirror_dir: None,
+            mirror_strict: false,
             prefix_length: 8,
+            prefix_format: "{prefix}.{name}".to_string(),
             max_file_size: 1_000_000_000,
             max_file_name_length: 200,
             stats_interval: 60,
+            full_refresh_interval: 3600,
             http: Default::default(),
             logging: Default::default(),
             allocate: true,
+            favicon: None,
+            max_open_files: 0,
+            preload: HashMap::new(),
+            per_ip_daily_bytes: 0,
+            name_policy: Default::default(),
+            control_chars: Default::default(),
+            empty_name: Default::default(),
+            admin_token: None,
+            public_url: None,
+            created_response: true,
+            max_decompression_ratio: 100,
+            sync_on_upload: false,
+            reservation_ttl: 3600,
+            shard: false,
+            shard_length: 2,
+            strong_etags: false,
+            require_content_length: true,
+            allowed_methods: ["GET", "HEAD", "PUT", "POST", "DELETE", "OPTIONS"]
+                .map(str::to_string)
+                .to_vec(),
+            upload_tokens: HashMap::new(),
+            storage_full_hook: Vec::new(),
+            resume_free_space: 100_000_000,
+            metrics: false,
+            store_original_names: false,
+            max_files: 0,
+            security_headers: HashMap::from([(
+                "X-Content-Type-Options".to_string(),
+                "nosniff".to_string(),
+            )]),
+            maintenance: false,
+            maintenance_page: None,
+            maintenance_retry_after: 300,
+            case_insensitive_names: false,
+            store_checksums: false,
+            reject_double_extension: false,
+            benign_double_extensions: ["tar.gz", "tar.bz2", "tar.xz", "tar.zst", "min.js"]
+                .map(String::from)
+                .to_vec(),
+            auth_failure_threshold: 5,
+            auth_failure_delay: 500,
+            max_json_response_size: 64_000_000,
+            allowed_extensions: None,
+            blocked_extensions: Vec::new(),
+            file_ttl_secs: None,
+            reap_interval: 600,
+            mime_sniffing: false,
+            blocked_mime: Vec::new(),
+            min_upload_rate: 0,
+            min_upload_rate_grace: 10,
         }
     }
 }
 
+impl Config {
+    /// Applies `prefix_format` to a random prefix and a file name.
+    pub fn prefixed_name(&self, prefix: &str, name: &str) -> String {
+        self.prefix_format
+            .replace("{prefix}", prefix)
+            .replace("{name}", name)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.prefix_length > 0
+            && !(self.prefix_format.contains("{prefix}") && self.prefix_format.contains("{name}"))
+        {
+            return Err(ConfigError::InvalidPrefixFormat);
+        }
+        // the prefixed name is joined onto upload_dir as is
+        if self.prefix_length > 0
+            && (self.prefix_format.contains(['/', '\\']) || self.prefix_format.contains(".."))
+        {
+            return Err(ConfigError::UnsafePrefixFormat);
+        }
+        if let Some(method) = self
+            .allowed_methods
+            .iter()
+            .find(|method| Method::from_bytes(method.as_bytes()).is_err())
+        {
+            return Err(ConfigError::InvalidMethod(method.clone()));
+        }
+        Ok(())
+    }
+
+    /// The subdirectory a file is stored in when sharding is enabled.
+    pub fn shard_of(&self, file_name: &str) -> Option<String> {
+        self.shard.then(|| {
+            file_name
+                .chars()
+                .take(self.shard_length.max(1))
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect()
+        })
+    }
+
+    /// The name a file is stored under, lowercased with
+    /// `case_insensitive_names` so names only differing in case collide on
+    /// every filesystem, not just case-insensitive ones.
+    pub fn normalize_name<'a>(&self, file_name: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive_names {
+            Cow::Owned(file_name.to_lowercase())
+        } else {
+            Cow::Borrowed(file_name)
+        }
+    }
+
+    fn path_in(&self, dir: &str, file_name: &str) -> PathBuf {
+        let file_name = &*self.normalize_name(file_name);
+        let dir = Path::new(dir);
+        match self.shard_of(file_name) {
+            Some(shard) => dir.join(shard).join(file_name),
+            None => dir.join(file_name),
+        }
+    }
+
+    /// Checks the extension of a stored name (after the prefix was applied)
+    /// against `allowed_extensions` and `blocked_extensions`. Names without
+    /// one have the empty extension.
+    pub fn extension_allowed(&self, file_name: &str) -> bool {
+        let extension = match file_name.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => extension,
+            _ => "",
+        };
+        let matches = |listed: &String| {
+            listed
+                .trim_start_matches('.')
+                .eq_ignore_ascii_case(extension)
+        };
+
+        self.allowed_extensions
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(matches))
+            && !self.blocked_extensions.iter().any(matches)
+    }
+
+    /// Whether `blocked_mime` has a MIME type, or its `type/*`.
+    pub fn mime_blocked(&self, mime_type: &str) -> bool {
+        let main_type = mime_type.split('/').next().unwrap_or_default();
+        self.blocked_mime.iter().any(|blocked| {
+            blocked.eq_ignore_ascii_case(mime_type)
+                || blocked
+                    .strip_suffix("/*")
+                    .is_some_and(|blocked| blocked.eq_ignore_ascii_case(main_type))
+        })
+    }
+
+    /// Where a file is stored on disk.
+    pub fn file_path(&self, file_name: &str) -> PathBuf {
+        self.path_in(&self.upload_dir, file_name)
+    }
+
+    /// Where the copy of a file is kept in `mirror_dir`, laid out the same as
+    /// `upload_dir`.
+    pub fn mirror_path(&self, file_name: &str) -> Option<PathBuf> {
+        self.mirror_dir
+            .as_deref()
+            .map(|mirror_dir| self.path_in(mirror_dir, file_name))
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum FindConfigError {
     #[error("No configuration file found")]
@@ -92,15 +340,46 @@ pub enum FindConfigError {
     IoError(#[from] std::io::Error),
 }
 
-pub async fn load_from(config_file: &Path) -> Result<Config> {
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("No configuration file found")]
+    NotFound,
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    ParseError(#[from] toml::de::Error),
+    #[error("prefix_format must contain both {{prefix}} and {{name}}")]
+    InvalidPrefixFormat,
+    #[error("prefix_format must not contain path separators or ..")]
+    UnsafePrefixFormat,
+    #[error("allowed_methods contains an invalid method: {0:?}")]
+    InvalidMethod(String),
+}
+
+impl From<FindConfigError> for ConfigError {
+    fn from(value: FindConfigError) -> Self {
+        match value {
+            FindConfigError::NoneFoundError => Self::NotFound,
+            FindConfigError::IoError(err) => Self::IoError(err),
+        }
+    }
+}
+
+pub async fn load_from(config_file: &Path) -> Result<Config, ConfigError> {
     let mut file = OpenOptions::new().read(true).open(config_file).await?;
     let metadata = file.metadata().await?;
     let mut buf = String::with_capacity(metadata.len() as usize);
     file.read_to_string(&mut buf).await?;
-    Ok(toml::from_str(buf.as_str())?)
+    let mut config: Config = toml::from_str(buf.as_str())?;
+    // request methods are case-sensitive, "get" would never match a GET
+    for method in &mut config.allowed_methods {
+        method.make_ascii_uppercase();
+    }
+    config.validate()?;
+    Ok(config)
 }
 
-pub async fn load() -> Result<(Config, PathBuf)> {
+pub async fn load() -> Result<(Config, PathBuf), ConfigError> {
     let config_file = find_config()?;
     Ok((load_from(&config_file).await?, config_file))
 }
@@ -137,3 +416,82 @@ pub fn find_config() -> Result<PathBuf, FindConfigError> {
         Err(FindConfigError::NoneFoundError)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_prefixed_names() {
+        let config = Config::default();
+        assert_eq!(config.prefixed_name("abc", "a.txt"), "abc.a.txt");
+
+        let config = Config {
+            prefix_format: "{name}-{prefix}".into(),
+            ..Config::default()
+        };
+        assert_eq!(config.prefixed_name("abc", "a.txt"), "a.txt-abc");
+    }
+
+    #[test]
+    fn validates_prefix_format() {
+        let with_format = |prefix_format: &str, prefix_length| Config {
+            prefix_format: prefix_format.into(),
+            prefix_length,
+            ..Config::default()
+        };
+
+        assert!(Config::default().validate().is_ok());
+        assert!(matches!(
+            with_format("{name}", 8).validate(),
+            Err(ConfigError::InvalidPrefixFormat)
+        ));
+        for unsafe_format in ["{prefix}/{name}", "{prefix}\\{name}", "..{prefix}{name}"] {
+            assert!(matches!(
+                with_format(unsafe_format, 8).validate(),
+                Err(ConfigError::UnsafePrefixFormat)
+            ));
+        }
+        // the format is unused without a prefix
+        assert!(with_format("{name}", 0).validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn reports_load_errors() {
+        let dir = env::temp_dir();
+        let missing = dir.join(format!("bingus-files-missing-{}.toml", std::process::id()));
+        assert!(matches!(
+            load_from(&missing).await,
+            Err(ConfigError::IoError(err)) if err.kind() == std::io::ErrorKind::NotFound
+        ));
+
+        let malformed = dir.join(format!(
+            "bingus-files-malformed-{}.toml",
+            std::process::id()
+        ));
+        fs::write(&malformed, "upload_dir = \n[http\n").unwrap();
+        let result = load_from(&malformed).await;
+        fs::remove_file(&malformed).unwrap();
+        assert!(matches!(result, Err(ConfigError::ParseError(_))));
+
+        assert!(matches!(
+            ConfigError::from(FindConfigError::NoneFoundError),
+            ConfigError::NotFound
+        ));
+    }
+
+    #[tokio::test]
+    async fn normalizes_allowed_methods() {
+        let path =
+            env::temp_dir().join(format!("bingus-files-methods-{}.toml", std::process::id()));
+
+        fs::write(&path, "allowed_methods = [\"get\", \"Put\"]\n").unwrap();
+        let result = load_from(&path).await;
+        assert_eq!(result.unwrap().allowed_methods, ["GET", "PUT"]);
+
+        fs::write(&path, "allowed_methods = [\"GET\", \"GE T\"]\n").unwrap();
+        let result = load_from(&path).await;
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(ConfigError::InvalidMethod(method)) if method == "GE T"));
+    }
+}
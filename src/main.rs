@@ -1,44 +1,80 @@
 #![feature(async_closure, io_error_more, let_chains, addr_parse_ascii)]
 
+mod api;
+mod auth;
 mod config;
+mod meta;
+mod metrics;
+mod proxy;
 mod silly;
 
-use crate::config::{Config, FileEnum, FindConfigError};
+use crate::auth::{Anonymous, Authenticator, Identity, StaticTokens};
+use crate::config::{Config, ConfigError, ControlCharPolicy, EmptyNamePolicy, FileEnum};
+use crate::meta::Sidecar;
+use crate::metrics::Metrics;
 use crate::silly::*;
 use anyhow::Result;
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder};
 use axum::{
-    body::Body,
-    extract::{ConnectInfo, Path, Request, State},
-    http::{HeaderMap, StatusCode},
+    body::{Body, Bytes},
+    error_handling::HandleErrorLayer,
+    extract::{
+        connect_info::Connected, ConnectInfo, DefaultBodyLimit, FromRequest, Multipart, Path,
+        Query, Request, State,
+    },
+    http::{
+        header::{
+            ACCEPT_ENCODING, ALLOW, CACHE_CONTROL, CONNECTION, CONTENT_DISPOSITION,
+            CONTENT_ENCODING, CONTENT_TYPE, ETAG, HOST, IF_NONE_MATCH, LINK, LOCATION, RETRY_AFTER,
+            TRANSFER_ENCODING, VARY,
+        },
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri,
+    },
     middleware::{from_fn_with_state, Next},
     response::{IntoResponse, Response},
-    routing::{get, get_service, put},
+    routing::{delete, get, get_service, post, put},
     Router,
 };
-use futures::TryStreamExt;
+use filetime::{set_file_mtime, FileTime};
+use futures::{Stream, StreamExt, TryStreamExt};
 use humansize::{format_size, DECIMAL};
 use owo_colors::{OwoColorize, Stream::Stderr};
-use serde::Serialize;
-use std::net::IpAddr;
+use qrcode::{render::svg, QrCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, Ipv4Addr};
 use std::{
-    fs::read_dir,
+    collections::{HashMap, VecDeque},
+    fs::{read_dir, DirEntry, Metadata},
     path,
+    pin::Pin,
+    process,
     str::FromStr,
-    sync::{Arc, RwLock},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use std::{io, net::SocketAddr};
 use thiserror::Error;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::{
     fs::try_exists,
-    net::TcpListener,
-    time::{sleep, Instant},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{sleep, timeout, Instant},
 };
-use tokio_util::io::StreamReader;
-use tower::limit::ConcurrencyLimitLayer;
+use tokio_util::{
+    io::{ReaderStream, StreamReader},
+    sync::CancellationToken,
+};
+use tower::{limit::ConcurrencyLimitLayer, BoxError, Service, ServiceBuilder, ServiceExt};
 use tower_http::{compression::Compression, services::ServeDir};
 use tracing::level_filters::LevelFilter;
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument, Span};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 macro_rules! silly {
@@ -47,7 +83,29 @@ macro_rules! silly {
     };
 }
 
+const STATIC_DIR: &str = "static";
 const DEFAULT_LOG_PATH: &str = "bingus-files_%Y-%m-%dT%H:%M:%S%:z.log";
+const FAVICON_CACHE_CONTROL: &str = "public, max-age=604800";
+/// Short, as the file the code points to may be deleted.
+const QR_CODE_CACHE_CONTROL: &str = "public, max-age=3600";
+const QUOTA_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const DELETE_TOKEN_HEADER: &str = "x-delete-token";
+const DELETE_TOKEN_LENGTH: usize = 32;
+const CANCEL_TOKEN_HEADER: &str = "x-cancel-token";
+const MAX_CONCURRENT_VERIFICATIONS: usize = 2;
+/// Left in `temp_dir` by the first upload that asks to expire, so the reaper
+/// is started again after a restart.
+const EXPIRING_MARKER: &str = "expiring";
+/// How much of an upload is read ahead for `mime_sniffing`, some formats are
+/// only recognizable a few KB in.
+const SNIFF_LENGTH: u64 = 8192;
+/// How many content hashes `strong_etags` keeps around.
+const ETAG_CACHE_CAPACITY: usize = 10_000;
+const MAX_AUTH_FAILURE_DELAY: Duration = Duration::from_secs(30);
+/// How long after its last failed attempt an IP's failures are forgotten.
+const AUTH_FAILURE_TTL: Duration = Duration::from_secs(60 * 60);
 
 #[derive(Debug, Clone, Serialize)]
 struct Stats {
@@ -56,340 +114,3770 @@ struct Stats {
     pub storage_used: u64,
 }
 
+/// What an upload was stored as.
 #[derive(Debug)]
-struct AppState {
-    pub config: Config,
-    pub stats: RwLock<Stats>,
+struct Stored {
+    pub file_name: String,
+    /// `None` when a retry was answered with an earlier upload, only the
+    /// request that stored the file is told its token.
+    pub delete_token: Option<String>,
 }
 
-type ArcState = Arc<AppState>;
+/// A file stored from a form, as the form upload is answered with.
+#[derive(Debug, Serialize)]
+struct FormUpload {
+    pub name: String,
+    pub delete_token: Option<String>,
+}
 
-#[derive(Debug, Error)]
-enum AppError {
-    #[error("Bad request")]
-    BadRequest,
-    #[error("File name too long")]
-    NameTooLong,
-    #[error("File was above max size")]
-    FileAboveMaxSize,
-    #[error("File already exists")]
-    Conflict,
-    #[error(transparent)]
-    IoError(#[from] io::Error),
+#[derive(Debug)]
+struct ActiveUpload {
+    pub requested_name: String,
+    /// `X-Cancel-Token` the upload was sent with, which lets it be cancelled
+    /// by the name it was sent as.
+    pub cancel_token: Option<String>,
+    pub cancel: CancellationToken,
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        error!("{}", self);
-        match self {
-            Self::BadRequest => silly!(BAD_REQUEST),
-            Self::NameTooLong => (StatusCode::BAD_REQUEST, "File name too long".to_string()),
-            Self::FileAboveMaxSize => silly!(PAYLOAD_TOO_LARGE),
-            Self::Conflict => silly!(CONFLICT),
-            Self::IoError(err) => match err.kind() {
-                io::ErrorKind::FilesystemQuotaExceeded => silly!(INSUFFICIENT_STORAGE),
-                _ => silly!(INTERNAL_SERVER_ERROR),
-            },
-        }
-        .into_response()
-    }
+/// What a request looked like the first time an idempotency key was used, so
+/// retries can be told apart from a reused key. The checksum is required with
+/// a key, the name and size alone don't say the content is the same.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UploadFingerprint {
+    pub requested_name: String,
+    pub size: Option<u64>,
+    pub checksum: String,
 }
 
-fn refresh_stats(config: &Config) -> Result<Stats> {
-    let files_dir = read_dir(&config.upload_dir)?;
+/// Whose idempotency keys a key is looked up among, so clients can't replay
+/// (or block) each other's uploads by sending the same key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum IdempotencyScope {
+    Identity(String),
+    Ip(IpAddr),
+}
 
-    let mut files_stored = 0;
-    let mut storage_used = 0;
+#[derive(Debug)]
+struct IdempotentUpload {
+    pub fingerprint: UploadFingerprint,
+    /// `None` while the first upload with the key is still going.
+    pub file_name: Option<String>,
+    pub created: Instant,
+}
 
-    for file in files_dir {
-        let file = file?;
-        let metadata = file.metadata()?;
+/// What an IP uploaded within the last `QUOTA_WINDOW`.
+#[derive(Debug, Default)]
+struct IpUploads {
+    pub finished: VecDeque<(Instant, u64)>,
+    /// Bytes claimed by uploads that are still going.
+    pub pending: u64,
+}
 
-        if metadata.is_file() {
-            files_stored += 1;
-            storage_used += metadata.len();
-        }
+impl IpUploads {
+    fn finished_in_window(&self) -> impl Iterator<Item = u64> + '_ {
+        self.finished
+            .iter()
+            .filter(|(time, _)| time.elapsed() < QUOTA_WINDOW)
+            .map(|(_, size)| *size)
     }
 
-    Ok(Stats {
-        max_file_size: config.max_file_size,
-        files_stored,
-        storage_used,
-    })
+    /// Counts uploads still going as if they were done, so concurrent ones
+    /// can't all fit in what is left.
+    fn used(&self) -> u64 {
+        self.finished_in_window().sum::<u64>() + self.pending
+    }
 }
 
-async fn get_stats(State(state): State<ArcState>) -> Slonkable<Stats> {
-    state.stats.read().unwrap().clone().into()
+#[derive(Debug, Serialize)]
+struct IpActivity {
+    pub ip: IpAddr,
+    pub uploads: usize,
+    pub bytes: u64,
 }
 
-async fn upload(
-    State(state): State<ArcState>,
-    ConnectInfo(connect_info): ConnectInfo<SocketAddr>,
-    Path(path): Path<String>,
-    headers: HeaderMap,
-    body: Body,
-) -> Result<String, AppError> {
-    let file_size = match headers
-        .get("content-length")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.parse::<u64>().ok())
-    {
-        Some(content_length) => content_length,
-        None => return Err(AppError::BadRequest),
-    };
+#[derive(Debug)]
+struct Reservation {
+    pub token: String,
+    pub expires: Instant,
+}
 
-    if file_size > state.config.max_file_size {
-        return Err(AppError::FileAboveMaxSize);
+#[derive(Debug, Serialize)]
+struct ReservationResponse {
+    pub name: String,
+    pub url: String,
+    pub token: String,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ValidateQuery {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ValidateResponse {
+    pub valid: bool,
+    pub reason: Option<String>,
+    pub sanitized: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Verification {
+    pub ok: bool,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// What an expiry sweep deleted.
+#[derive(Debug, Default, Serialize)]
+struct Expired {
+    pub deleted: Vec<String>,
+    pub freed: u64,
+}
+
+#[derive(Debug)]
+struct AppState {
+    pub config: Config,
+    pub stats: RwLock<Stats>,
+    /// Uploads still going that count against `max_files`, only changed
+    /// while holding the stats lock so the two are checked together.
+    pub pending_files: AtomicU64,
+    pub uploads: RwLock<HashMap<String, ActiveUpload>>,
+    pub open_files: Option<Arc<Semaphore>>,
+    /// Uploads per IP counting against `per_ip_daily_bytes`.
+    pub ip_uploads: RwLock<HashMap<IpAddr, IpUploads>>,
+    pub idempotency_keys: RwLock<HashMap<(IdempotencyScope, String), IdempotentUpload>>,
+    pub reservations: RwLock<HashMap<String, Reservation>>,
+    /// Content hashes for strong ETags, along with the size and modification
+    /// time they were computed for.
+    pub etag_cache: RwLock<HashMap<path::PathBuf, (u64, SystemTime, String)>>,
+    /// Checks requests that write before they are handled.
+    pub authenticator: Box<dyn Authenticator>,
+    /// Set when an upload ran out of space, new uploads are refused until
+    /// the stats loop sees enough free space again.
+    pub read_only: AtomicBool,
+    /// Request latencies for `/api/v1/metrics`, `None` when metrics are disabled.
+    pub metrics: Option<Metrics>,
+    /// `security_headers` from the config, parsed once.
+    pub security_headers: HeaderMap,
+    /// Writes are refused while this is set, toggled with `/api/v1/maintenance`.
+    pub maintenance: AtomicBool,
+    /// Failed authentications in a row per IP, and when the last one was.
+    pub auth_failures: RwLock<HashMap<IpAddr, (u32, Instant)>>,
+    /// Files being hashed by `/api/v1/verify`, which anyone can ask for.
+    pub verify_permits: Semaphore,
+    /// Set once the reaper runs, or would if `reap_interval` wasn't 0.
+    pub reaper_started: AtomicBool,
+}
+
+impl AppState {
+    /// The stats are plain counters, so they are still usable after a panic
+    /// poisoned the lock.
+    fn stats(&self) -> RwLockReadGuard<'_, Stats> {
+        self.stats.read().unwrap_or_else(PoisonError::into_inner)
     }
 
-    if path.len() > state.config.max_file_name_length {
-        return Err(AppError::NameTooLong);
+    fn stats_mut(&self) -> RwLockWriteGuard<'_, Stats> {
+        self.stats.write().unwrap_or_else(PoisonError::into_inner)
     }
 
-    let file_name = if state.config.prefix_length > 0 {
-        format!(
-            "{}.{}",
-            get_random_prefix(state.config.prefix_length),
-            sanitize_file_name(&path),
-        )
-    } else {
-        let new_name = sanitize_file_name(&path);
-        if new_name == "." || new_name == ".." {
-            return Err(AppError::BadRequest);
+    /// Waits until a file may be opened without going over `max_open_files`.
+    async fn open_file_permit(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.open_files {
+            Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+            None => None,
         }
-        // TODO: con, prn, aux, etc. on windows
-        new_name
-    };
+    }
 
-    let file_path = path::Path::new(&state.config.upload_dir).join(&file_name);
+    /// Takes one of `max_files` for an upload, `None` when there is no limit.
+    fn reserve_file_slot(self: &ArcState) -> Result<Option<FileSlot>, AppError> {
+        if self.config.max_files == 0 {
+            return Ok(None);
+        }
 
-    if tokio::fs::try_exists(&file_path).await? {
-        return Err(AppError::Conflict);
+        let stats = self.stats_mut();
+        if stats.files_stored + self.pending_files.load(Ordering::Relaxed) >= self.config.max_files
+        {
+            return Err(AppError::TooManyFiles);
+        }
+        self.pending_files.fetch_add(1, Ordering::Relaxed);
+        drop(stats);
+
+        Ok(Some(FileSlot {
+            state: self.clone(),
+        }))
     }
 
-    if let Err(err) = async {
-        info!(
-            "{} is uploading file {} ({})",
-            if state.config.http.behind_proxy {
-                get_ip(&headers)
-            } else {
-                None
+    /// Claims an upload's size out of its IP's `per_ip_daily_bytes`, `None`
+    /// when there is no quota. Uploads of unknown size start out claiming
+    /// nothing, but are refused if nothing is left.
+    fn reserve_quota(
+        self: &ArcState,
+        ip: IpAddr,
+        size: Option<u64>,
+    ) -> Result<Option<QuotaReservation>, AppError> {
+        let limit = self.config.per_ip_daily_bytes;
+        if limit == 0 {
+            return Ok(None);
+        }
+
+        let mut ip_uploads = self.ip_uploads.write().unwrap();
+        let uploads = ip_uploads.entry(ip).or_default();
+        let used = uploads.used();
+        let size = size.unwrap_or(0);
+        if used.saturating_add(size) > limit || used >= limit {
+            return Err(AppError::QuotaExceeded);
+        }
+        uploads.pending += size;
+
+        Ok(Some(QuotaReservation {
+            state: self.clone(),
+            ip,
+            claimed: size,
+        }))
+    }
+
+    fn record_upload(&self, ip: IpAddr, size: u64) {
+        self.ip_uploads
+            .write()
+            .unwrap()
+            .entry(ip)
+            .or_default()
+            .finished
+            .push_back((Instant::now(), size));
+    }
+
+    fn prune_ip_uploads(&self) {
+        self.ip_uploads.write().unwrap().retain(|_, uploads| {
+            while uploads
+                .finished
+                .front()
+                .is_some_and(|(time, _)| time.elapsed() >= QUOTA_WINDOW)
+            {
+                uploads.finished.pop_front();
             }
-            .unwrap_or_else(|| connect_info.ip().to_string()),
-            file_name.if_supports_color(Stderr, |text| text.bold()),
-            format_size(file_size, DECIMAL),
-        );
+            !uploads.finished.is_empty() || uploads.pending > 0
+        });
+    }
 
-        let mut out_file = tokio::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&file_path)
-            .await?;
+    fn prune_idempotency_keys(&self) {
+        self.idempotency_keys.write().unwrap().retain(|_, upload| {
+            upload.file_name.is_none() || upload.created.elapsed() < IDEMPOTENCY_KEY_TTL
+        });
+    }
 
-        if file_size > 0 && state.config.allocate {
-            debug!(
-                "allocating {} for '{}'",
-                format_size(file_size, DECIMAL),
-                file_name
-            );
+    fn prune_reservations(&self) {
+        self.reservations
+            .write()
+            .unwrap()
+            .retain(|_, reservation| reservation.expires > Instant::now());
+    }
 
-            out_file.set_len(file_size).await?;
+    fn enter_read_only(&self) {
+        if self.read_only.swap(true, Ordering::Relaxed) {
+            return;
         }
 
-        let mut reader = StreamReader::new(
-            body.into_data_stream()
-                .map_err(|err| io::Error::new(io::ErrorKind::Other, err)),
-        );
+        warn!("storage is full, refusing uploads until space is freed");
 
-        tokio::io::copy(&mut reader, &mut out_file).await?;
+        let Some((program, args)) = self.config.storage_full_hook.split_first() else {
+            return;
+        };
+        match tokio::process::Command::new(program).args(args).spawn() {
+            Ok(mut child) => {
+                tokio::spawn(async move {
+                    if let Err(err) = child.wait().await {
+                        error!("Error while waiting for storage_full_hook: {}", err);
+                    }
+                });
+            }
+            Err(err) => error!("Error while running storage_full_hook: {}", err),
+        }
+    }
 
-        let mut stats = state.stats.write().unwrap();
+    /// Leaves read-only mode once there is `resume_free_space` free again.
+    fn check_read_only(&self) {
+        if !self.read_only.load(Ordering::Relaxed) {
+            return;
+        }
 
-        stats.files_stored += 1;
-        stats.storage_used += file_size;
+        match fs2::available_space(&self.config.upload_dir) {
+            Ok(available) if available >= self.config.resume_free_space => {
+                info!(
+                    "{} free, accepting uploads again",
+                    format_size(available, DECIMAL)
+                );
+                self.read_only.store(false, Ordering::Relaxed);
+            }
+            Ok(_) => {}
+            Err(err) => error!("Error while checking free space: {}", err),
+        }
+    }
 
-        Ok::<_, io::Error>(())
+    fn is_reserved(&self, file_name: &str) -> bool {
+        self.reservations
+            .read()
+            .unwrap()
+            .get(file_name)
+            .is_some_and(|reservation| reservation.expires > Instant::now())
     }
-    .await
-    {
-        trace!("cleaning up failed upload of '{}'", file_name);
-        match tokio::fs::try_exists(&file_path).await {
-            Ok(exists) => {
-                if exists {
-                    if let Err(err) = tokio::fs::remove_file(&file_path).await {
-                        error!(
-                            "Error while removing file '{}': {}",
-                            file_path.display(),
-                            err
-                        );
-                    };
+
+    /// Deletes every file older than `file_ttl_secs` going by its mtime, or
+    /// past the expiry its uploader asked for, along with its sidecar and
+    /// mirror.
+    async fn expire_files(&self) -> io::Result<Expired> {
+        let now = SystemTime::now();
+        let cutoff = self
+            .config
+            .file_ttl_secs
+            .map(|ttl| now - Duration::from_secs(ttl));
+
+        let upload_dir = self.config.upload_dir.clone();
+        let shard = self.config.shard;
+        let files = tokio::task::spawn_blocking(move || {
+            let mut files = Vec::new();
+            walk_files(
+                path::Path::new(&upload_dir),
+                shard,
+                &mut |file, metadata| {
+                    files.push((
+                        file.file_name().to_string_lossy().into_owned(),
+                        file.path(),
+                        metadata.len(),
+                        metadata.modified().ok(),
+                    ));
+                },
+            )
+            .map(|()| files)
+        })
+        .await??;
+
+        let mut expired = Expired::default();
+        for (file_name, file_path, size, modified) in files {
+            // still being written, its mtime isn't pinned yet
+            if self.uploads.read().unwrap().contains_key(&file_name) {
+                continue;
+            }
+
+            let past_ttl =
+                cutoff.is_some_and(|cutoff| modified.is_some_and(|modified| modified < cutoff));
+            if !past_ttl {
+                let expires_at = match Sidecar::load(&self.config, &file_name).await {
+                    Ok(sidecar) => sidecar.and_then(|sidecar| sidecar.expires_at),
+                    Err(err) => {
+                        error!("Error while reading sidecar of '{}': {}", file_name, err);
+                        None
+                    }
+                };
+                if !expires_at
+                    .is_some_and(|expires_at| UNIX_EPOCH + Duration::from_secs(expires_at) <= now)
+                {
+                    continue;
                 }
             }
-            Err(err) => {
-                error!(
-                    "Error while checking if file '{}' exists: {}",
-                    file_path.display(),
-                    err
-                )
+
+            match self.delete_stored(&file_name, &file_path, size).await {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) => {
+                    error!("Error while expiring '{}': {}", file_name, err);
+                    continue;
+                }
             }
-        };
+            info!("expired '{}' ({})", file_name, format_size(size, DECIMAL));
 
-        Err(err.into())
-    } else {
-        Ok(urlencoding::encode(&file_name).to_string())
+            expired.deleted.push(file_name);
+            expired.freed += size;
+        }
+
+        Ok(expired)
     }
-}
 
-async fn logger(
-    State(state): State<ArcState>,
-    connect_info: ConnectInfo<SocketAddr>,
-    request: Request,
-    next: Next,
-) -> Response {
-    let ip = if state.config.http.behind_proxy {
-        get_ip(request.headers())
-            .and_then(|x| IpAddr::parse_ascii(x.as_bytes()).ok())
-            .unwrap_or_else(|| connect_info.ip())
-    } else {
-        connect_info.ip()
-    };
+    /// Deletes a stored file along with everything kept about it, and takes
+    /// it out of the stats. Only fails if the file itself couldn't be deleted,
+    /// and returns `false` if it was already gone, in which case whoever
+    /// deleted it has done the rest.
+    async fn delete_stored(
+        &self,
+        file_name: &str,
+        file_path: &path::Path,
+        size: u64,
+    ) -> io::Result<bool> {
+        if !remove_file_if_exists(file_path).await? {
+            return Ok(false);
+        }
+        self.etag_cache.write().unwrap().remove(file_path);
 
-    let path = request.uri().path().to_owned();
-    let method = request.method().to_owned();
+        if let Err(err) = Sidecar::remove(&self.config, file_name).await {
+            error!("Error while removing sidecar of '{}': {}", file_name, err);
+        }
+        if let Some(mirror_path) = self.config.mirror_path(file_name)
+            && let Err(err) = remove_file_if_exists(&mirror_path).await
+        {
+            error!(
+                "Error while removing file '{}': {}",
+                mirror_path.display(),
+                err
+            );
+        }
 
-    let start = Instant::now();
-    let response = next.run(request).await;
-    let elapsed = start.elapsed();
+        let mut stats = self.stats_mut();
+        stats.files_stored = stats.files_stored.saturating_sub(1);
+        stats.storage_used = stats.storage_used.saturating_sub(size);
 
-    let status_code = response.status().as_u16();
+        Ok(true)
+    }
 
-    info!(
-        "({}) {} {} {} ({:#?})",
-        ip,
-        status_code.if_supports_color(Stderr, |text| text
-            .style(color_status_code(status_code).bold())),
-        method.if_supports_color(Stderr, |text| text.bold()),
-        path,
-        elapsed
-    );
+    /// Fails the upload with `mirror_strict`, otherwise it carries on without
+    /// the mirror.
+    fn mirror_failed(&self, file_name: &str, err: io::Error) -> io::Result<()> {
+        if self.config.mirror_strict {
+            return Err(err);
+        }
+        error!(
+            "Error while mirroring '{}', not mirroring it: {}",
+            file_name, err
+        );
+        Ok(())
+    }
 
-    response
-}
+    /// Applies the `control_chars` policy to a requested name.
+    fn remove_control_chars(&self, path: &str) -> Result<String, AppError> {
+        if !has_control_chars(path) {
+            return Ok(path.to_string());
+        }
+        match self.config.control_chars {
+            ControlCharPolicy::Reject => Err(AppError::ControlChars),
+            ControlCharPolicy::Strip => Ok(strip_control_chars(path)),
+        }
+    }
 
-#[tokio::main]
-async fn main() {
-    let config = match config::load().await {
-        Ok(config) => {
-            eprintln!("Loaded configuration from {}", config.1.display());
-            config.0
+    /// Turns the name a client asked for into the name the file is stored as.
+    fn generate_file_name(&self, path: &str) -> Result<String, AppError> {
+        if path.len() > self.config.max_file_name_length {
+            return Err(AppError::NameTooLong);
         }
-        Err(error) => {
-            eprintln!("Error loading configuration: {}", error);
-            if error.is::<FindConfigError>()
-                && matches!(
-                    error.downcast::<FindConfigError>().unwrap(),
-                    FindConfigError::NoneFoundError
-                )
-            {
-                eprintln!("Using default configuration");
-                Config::default()
-            } else {
-                unimplemented!()
-            }
+
+        let path = &self.remove_control_chars(path)?;
+        let new_name = sanitize_file_name(path, self.config.name_policy);
+
+        if new_name.trim().is_empty() {
+            return match self.config.empty_name {
+                EmptyNamePolicy::PrefixOnly if self.config.prefix_length > 0 => {
+                    let prefix = get_random_prefix(self.config.prefix_length);
+                    if !self.config.extension_allowed(&prefix) {
+                        return Err(AppError::ForbiddenExtension);
+                    }
+                    Ok(self.config.normalize_name(&prefix).into_owned())
+                }
+                _ => Err(AppError::EmptyName),
+            };
         }
-    };
 
-    tracing_subscriber::registry()
-        .with(LevelFilter::from_str(&config.logging.level).unwrap())
-        .with(
-            config
-                .logging
-                .stderr
-                .then_some(tracing_subscriber::fmt::layer()),
-        )
-        .with(
-            match &config.logging.file {
-                FileEnum::Boolean(value) => value.then_some(DEFAULT_LOG_PATH),
-                FileEnum::Path(value) => Some(value.as_str()),
+        if self.config.reject_double_extension
+            && has_suspicious_double_extension(&new_name, &self.config.benign_double_extensions)
+        {
+            return Err(AppError::SuspiciousExtension);
+        }
+
+        let new_name = if self.config.prefix_length > 0 {
+            self.config
+                .prefixed_name(&get_random_prefix(self.config.prefix_length), &new_name)
+        } else {
+            if new_name == "." || new_name == ".." {
+                return Err(AppError::InvalidName);
             }
-            .map(|path| {
-                let time = chrono::Utc::now();
-                let path = time.format(path).to_string();
-                let file = std::fs::OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .open(path)
-                    .unwrap();
-                tracing_subscriber::fmt::layer()
-                    .json()
-                    .with_writer(file)
-                    .with_ansi(false)
-            }),
-        )
-        .init();
+            new_name
+        };
 
-    debug!("{:#?}", &config);
+        if !self.config.extension_allowed(&new_name) {
+            return Err(AppError::ForbiddenExtension);
+        }
 
-    if !try_exists(&config.upload_dir).await.unwrap() {
-        debug!("Creating upload directory");
-        tokio::fs::create_dir_all(&config.upload_dir).await.unwrap();
+        Ok(self.config.normalize_name(&new_name).into_owned())
     }
-    if !try_exists(&config.temp_dir).await.unwrap() {
-        debug!("Creating temp directory");
-        tokio::fs::create_dir_all(&config.temp_dir).await.unwrap();
+
+    /// Finds the file a GET request would be served from, static files first.
+    async fn served_file(&self, uri: &Uri) -> Option<(path::PathBuf, Metadata)> {
+        let decoded = urlencoding::decode(uri.path()).ok()?;
+        let relative = path::Path::new(decoded.trim_start_matches('/'));
+        if relative
+            .components()
+            .any(|component| !matches!(component, path::Component::Normal(_)))
+        {
+            return None;
+        }
+
+        let mut static_path = path::Path::new(STATIC_DIR).join(relative);
+        if decoded.ends_with('/') {
+            static_path.push("index.html");
+        }
+
+        let candidates = [
+            Some(static_path),
+            (relative.components().count() == 1).then(|| self.config.file_path(&decoded[1..])),
+        ];
+        for candidate in candidates.into_iter().flatten() {
+            if let Ok(metadata) = tokio::fs::metadata(&candidate).await
+                && metadata.is_file()
+            {
+                return Some((candidate, metadata));
+            }
+        }
+
+        None
     }
 
-    let stats = refresh_stats(&config).unwrap();
+    async fn etag(&self, path: path::PathBuf, metadata: &Metadata) -> Option<String> {
+        let modified = metadata.modified().ok()?;
+        if !self.config.strong_etags {
+            return Some(weak_etag(metadata.len(), modified));
+        }
 
-    let state = Arc::new(AppState {
-        config: config.clone(),
-        stats: RwLock::new(stats),
-    });
+        if let Some((len, cached_modified, etag)) = self.etag_cache.read().unwrap().get(&path)
+            && *len == metadata.len()
+            && *cached_modified == modified
+        {
+            return Some(etag.clone());
+        }
 
-    let serve_files = ServeDir::new(&config.upload_dir).precompressed_gzip();
-    let serve_static =
-        Compression::new(ServeDir::new(path::Path::new("static")).fallback(serve_files));
+        let etag = format!("\"{}\"", hash_file(path.clone()).await.ok()?);
 
-    let app = Router::new()
-        .nest_service(
-            "/",
-            get_service(serve_static).fallback_service(
-                Router::new().route("/:file", put(upload).with_state(state.clone())),
-            ),
+        let mut etag_cache = self.etag_cache.write().unwrap();
+        if etag_cache.len() >= ETAG_CACHE_CAPACITY && !etag_cache.contains_key(&path) {
+            // whichever goes costs one more hash the next time it is served
+            if let Some(evicted) = etag_cache.keys().next().cloned() {
+                etag_cache.remove(&evicted);
+            }
+        }
+        etag_cache.insert(path, (metadata.len(), modified, etag.clone()));
+        Some(etag)
+    }
+
+    fn upload_response(&self, file_name: &str) -> Response {
+        let encoded_name = urlencoding::encode(file_name).to_string();
+        if self.config.created_response {
+            (
+                StatusCode::CREATED,
+                [(LOCATION, self.file_url(file_name))],
+                encoded_name,
+            )
+                .into_response()
+        } else {
+            encoded_name.into_response()
+        }
+    }
+
+    async fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        peer: &PeerAddr,
+    ) -> Result<Identity, AppError> {
+        let ip = self.client_ip(headers, peer);
+        match self.authenticator.authenticate(headers, ip).await {
+            Ok(identity) => {
+                self.auth_failures.write().unwrap().remove(&ip);
+                Ok(identity)
+            }
+            Err(err) => {
+                debug!("rejecting request from {}: {}", ip, err);
+                Err(self.auth_failed(ip).await)
+            }
+        }
+    }
+
+    /// How long the answer to this many failed authentications in a row is
+    /// held back, which doubles for every failure over the threshold.
+    fn auth_penalty(&self, failures: u32) -> Duration {
+        let threshold = self.config.auth_failure_threshold;
+        if threshold == 0 || failures <= threshold {
+            return Duration::ZERO;
+        }
+        let doublings = (failures - threshold - 1).min(16);
+        Duration::from_millis(self.config.auth_failure_delay)
+            .saturating_mul(1 << doublings)
+            .min(MAX_AUTH_FAILURE_DELAY)
+    }
+
+    /// Counts a failed authentication and tarpits the IP, answering it later
+    /// the more it failed in a row, so guessing tokens gets slower and slower.
+    async fn auth_failed(&self, ip: IpAddr) -> AppError {
+        let failures = {
+            let mut auth_failures = self.auth_failures.write().unwrap();
+            let (failures, last) = auth_failures.entry(ip).or_insert((0, Instant::now()));
+            *failures += 1;
+            *last = Instant::now();
+            *failures
+        };
+
+        let penalty = self.auth_penalty(failures);
+        if !penalty.is_zero() {
+            debug!(
+                "delaying {} by {:?} after {} failed authentications",
+                ip, penalty, failures
+            );
+            sleep(penalty).await;
+        }
+        AppError::Unauthorized
+    }
+
+    fn prune_auth_failures(&self) {
+        self.auth_failures
+            .write()
+            .unwrap()
+            .retain(|_, (_, last)| last.elapsed() < AUTH_FAILURE_TTL);
+    }
+
+    /// Penalizes failures like `authenticate` does, so the admin token can't
+    /// be guessed any faster than upload tokens.
+    async fn authorize_admin(&self, headers: &HeaderMap, peer: &PeerAddr) -> Result<(), AppError> {
+        let Some(admin_token) = &self.config.admin_token else {
+            return Err(AppError::NotFound);
+        };
+
+        let ip = self.client_ip(headers, peer);
+        match get_bearer_token(headers) {
+            Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => {
+                self.auth_failures.write().unwrap().remove(&ip);
+                Ok(())
+            }
+            _ => {
+                debug!("rejecting admin request from {}", ip);
+                Err(self.auth_failed(ip).await)
+            }
+        }
+    }
+
+    fn file_url(&self, file_name: &str) -> String {
+        format!(
+            "{}/{}",
+            self.config
+                .public_url
+                .as_deref()
+                .unwrap_or_default()
+                .trim_end_matches('/'),
+            urlencoding::encode(file_name)
         )
-        .route("/stats", get(get_stats))
-        .layer(from_fn_with_state(state.clone(), logger))
-        .with_state(state.clone());
-    let app = if config.http.concurrency_limit != 0 {
-        app.layer(ConcurrencyLimitLayer::new(config.http.concurrency_limit))
-    } else {
-        app
+    }
+
+    /// The forwarded address, if it should be trusted for this peer.
+    fn forwarded_ip(&self, headers: &HeaderMap, peer: &PeerAddr) -> Option<IpAddr> {
+        // only a local proxy can reach the unix socket, so it is always trusted
+        if self.config.http.behind_proxy || matches!(peer, PeerAddr::Unix) {
+            get_ip(headers).and_then(|x| IpAddr::parse_ascii(x.as_bytes()).ok())
+        } else {
+            None
+        }
+    }
+
+    fn client_ip(&self, headers: &HeaderMap, peer: &PeerAddr) -> IpAddr {
+        self.forwarded_ip(headers, peer)
+            .unwrap_or_else(|| match peer {
+                PeerAddr::Tcp(addr) => addr.ip(),
+                PeerAddr::Unix => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            })
+    }
+
+    /// How a client is shown in logs, which is its IP unless it came through
+    /// the unix socket without a forwarded address.
+    fn client_name(&self, headers: &HeaderMap, peer: &PeerAddr) -> String {
+        match (self.forwarded_ip(headers, peer), peer) {
+            (Some(ip), _) => ip.to_string(),
+            (None, PeerAddr::Tcp(addr)) => addr.ip().to_string(),
+            (None, PeerAddr::Unix) => "unix".to_string(),
+        }
+    }
+}
+
+/// Who is on the other end of a connection.
+#[derive(Debug, Clone)]
+enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl Connected<PeerAddr> for PeerAddr {
+    fn connect_info(target: PeerAddr) -> Self {
+        target
+    }
+}
+
+type ArcState = Arc<AppState>;
+
+/// One of `max_files` taken by an upload that is still going, given back when
+/// dropped. A stored file is counted in the stats before that.
+struct FileSlot {
+    state: ArcState,
+}
+
+impl Drop for FileSlot {
+    fn drop(&mut self) {
+        let _stats = self.state.stats_mut();
+        self.state.pending_files.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Bytes of `per_ip_daily_bytes` claimed by an upload that is still going,
+/// given back when dropped. A finished upload is recorded before that, so
+/// its bytes are never missing from the count.
+struct QuotaReservation {
+    state: ArcState,
+    ip: IpAddr,
+    claimed: u64,
+}
+
+impl QuotaReservation {
+    /// Claims more once an upload turns out bigger than it said it was, or
+    /// had no size to begin with.
+    fn grow(&mut self, size: u64) -> Result<(), AppError> {
+        if size <= self.claimed {
+            return Ok(());
+        }
+
+        let mut ip_uploads = self.state.ip_uploads.write().unwrap();
+        let uploads = ip_uploads.entry(self.ip).or_default();
+        if uploads.used() - self.claimed + size > self.state.config.per_ip_daily_bytes {
+            return Err(AppError::QuotaExceeded);
+        }
+        uploads.pending += size - self.claimed;
+        self.claimed = size;
+        Ok(())
+    }
+}
+
+impl Drop for QuotaReservation {
+    fn drop(&mut self) {
+        if let Some(uploads) = self.state.ip_uploads.write().unwrap().get_mut(&self.ip) {
+            uploads.pending = uploads.pending.saturating_sub(self.claimed);
+        }
+    }
+}
+
+/// Lets go of an idempotency key when dropped if its upload didn't finish, so
+/// it can be retried.
+struct IdempotencyGuard {
+    state: ArcState,
+    key: (IdempotencyScope, String),
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        let mut idempotency_keys = self.state.idempotency_keys.write().unwrap();
+        if idempotency_keys
+            .get(&self.key)
+            .is_some_and(|upload| upload.file_name.is_none())
+        {
+            idempotency_keys.remove(&self.key);
+        }
+    }
+}
+
+/// Removes an upload from the registry of active uploads when dropped, even if
+/// the handler future is dropped because the client went away.
+struct UploadGuard {
+    state: ArcState,
+    file_name: String,
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        self.state.uploads.write().unwrap().remove(&self.file_name);
+    }
+}
+
+#[derive(Debug, Error)]
+enum AppError {
+    #[error("Bad request")]
+    BadRequest,
+    #[error("File name too long")]
+    NameTooLong,
+    #[error("File name is empty")]
+    EmptyName,
+    #[error("File name contains control characters")]
+    ControlChars,
+    #[error("Invalid file name")]
+    InvalidName,
+    #[error("File was above max size")]
+    FileAboveMaxSize,
+    #[error("File already exists")]
+    Conflict,
+    #[error("Precondition failed")]
+    PreconditionFailed,
+    #[error("Upload was cancelled")]
+    Cancelled,
+    #[error("Not found")]
+    NotFound,
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Upload quota exceeded")]
+    QuotaExceeded,
+    #[error("Unsupported content encoding")]
+    UnsupportedEncoding,
+    #[error("File name has a suspicious double extension")]
+    SuspiciousExtension,
+    #[error("File extension is not allowed")]
+    ForbiddenExtension,
+    #[error("File type is not allowed")]
+    BlockedContentType,
+    #[error("Upload was too slow")]
+    UploadTooSlow,
+    #[error("Idempotency key was already used for a different upload")]
+    IdempotencyKeyReused,
+    #[error("Upload with this idempotency key is still in progress")]
+    IdempotencyKeyInUse,
+    #[error("Storage is full")]
+    StorageFull,
+    #[error("Maximum number of files reached")]
+    TooManyFiles,
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+impl AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest
+            | Self::NameTooLong
+            | Self::EmptyName
+            | Self::ControlChars
+            | Self::InvalidName => StatusCode::BAD_REQUEST,
+            Self::FileAboveMaxSize => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Conflict | Self::Cancelled | Self::IdempotencyKeyInUse => StatusCode::CONFLICT,
+            Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+            Self::UnsupportedEncoding
+            | Self::SuspiciousExtension
+            | Self::ForbiddenExtension
+            | Self::BlockedContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::IdempotencyKeyReused | Self::ChecksumMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::StorageFull | Self::TooManyFiles => StatusCode::INSUFFICIENT_STORAGE,
+            Self::IoError(err) if is_storage_full(err) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::UploadTooSlow => StatusCode::REQUEST_TIMEOUT,
+            Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        error!("{}", self);
+        let status = self.status();
+        match self {
+            // closing the connection is the point, waiting for the rest of the
+            // body so it can be reused would let the client keep it busy
+            Self::UploadTooSlow => {
+                (status, [(CONNECTION, "close")], self.to_string()).into_response()
+            }
+            // these say more than the status does
+            Self::NameTooLong
+            | Self::Cancelled
+            | Self::IdempotencyKeyReused
+            | Self::IdempotencyKeyInUse
+            | Self::ChecksumMismatch => (status, self.to_string()).into_response(),
+            _ => (status, status.to_string()).into_response(),
+        }
+    }
+}
+
+/// Resolves once an upload has been slower than `min_upload_rate` on average,
+/// after giving it `min_upload_rate_grace` seconds to get going.
+async fn too_slow(config: &Config, progress: &AtomicU64) {
+    if config.min_upload_rate == 0 {
+        return std::future::pending().await;
+    }
+
+    let start = Instant::now();
+    sleep(Duration::from_secs(config.min_upload_rate_grace)).await;
+    loop {
+        let rate = progress.load(Ordering::Relaxed) as f64 / start.elapsed().as_secs_f64();
+        if rate < config.min_upload_rate as f64 {
+            debug!("upload is going at {:.0} B/s, giving up on it", rate);
+            return;
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn open_mirror(mirror_path: &path::Path) -> io::Result<tokio::fs::File> {
+    if let Some(dir) = mirror_path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::File::create(mirror_path).await
+}
+
+/// Returns whether there was a file to remove.
+async fn remove_file_if_exists(path: &path::Path) -> io::Result<bool> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Hex SHA-256 of a file, read on the blocking pool.
+async fn hash_file(path: path::PathBuf) -> io::Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await?
+}
+
+fn walk_files(
+    dir: &path::Path,
+    recursive: bool,
+    f: &mut impl FnMut(&DirEntry, &Metadata),
+) -> io::Result<()> {
+    for file in read_dir(dir)? {
+        let file = file?;
+        let metadata = file.metadata()?;
+
+        if metadata.is_file() {
+            f(&file, &metadata);
+        } else if recursive && metadata.is_dir() {
+            walk_files(&file.path(), recursive, f)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Points a request for a file at where it is stored: lowercased with
+/// `case_insensitive_names`, in its shard with `shard`.
+fn stored_uri(config: &Config, uri: &Uri) -> Option<Uri> {
+    if !config.shard && !config.case_insensitive_names {
+        return None;
+    }
+
+    let encoded_name = uri.path().strip_prefix('/')?;
+    if encoded_name.is_empty() || encoded_name.contains('/') {
+        return None;
+    }
+
+    let file_name = urlencoding::decode(encoded_name).ok()?;
+    let file_name = config.normalize_name(&file_name);
+    let encoded_name = urlencoding::encode(&file_name);
+
+    let mut path_and_query = match config.shard_of(&file_name) {
+        Some(shard) => format!("/{}/{}", shard, encoded_name),
+        None => format!("/{}", encoded_name),
     };
+    if let Some(query) = uri.query() {
+        path_and_query.push('?');
+        path_and_query.push_str(query);
+    }
+
+    Uri::builder().path_and_query(path_and_query).build().ok()
+}
+
+/// Counts the files in `upload_dir`, leaving out uploads still in progress,
+/// which are only counted once they are done.
+fn refresh_stats(config: &Config, in_progress: impl Fn(&str) -> bool) -> Result<Stats> {
+    let mut files_stored = 0;
+    let mut storage_used = 0;
+
+    walk_files(
+        path::Path::new(&config.upload_dir),
+        config.shard,
+        &mut |file, metadata| {
+            if in_progress(&file.file_name().to_string_lossy()) {
+                return;
+            }
+            files_stored += 1;
+            storage_used += metadata.len();
+        },
+    )?;
+
+    Ok(Stats {
+        max_file_size: config.max_file_size,
+        files_stored,
+        storage_used,
+    })
+}
+
+/// Deletes expired files every `reap_interval`. Only started once there can be
+/// any, right away with `file_ttl_secs` and otherwise by the first upload that
+/// asks to expire, as every run reads the sidecar of every file.
+fn start_reaper(state: &ArcState) {
+    if state.reaper_started.swap(true, Ordering::Relaxed) || state.config.reap_interval == 0 {
+        return;
+    }
 
     let state = state.clone();
     tokio::spawn(async move {
         loop {
-            sleep(Duration::from_secs(state.config.stats_interval)).await;
-            debug!("Refreshing stats");
-            let stats = refresh_stats(&state.config).unwrap();
-            *state.stats.write().unwrap() = stats;
+            sleep(Duration::from_secs(state.config.reap_interval)).await;
+            debug!("Expiring files");
+            if let Err(err) = state.expire_files().await {
+                error!("Error while expiring files: {}", err);
+            }
         }
     });
+}
 
-    let address = (config.http.host.as_str(), config.http.port);
-    let listener = TcpListener::bind(address).await.unwrap();
-    let local_addr = listener.local_addr().unwrap();
-    info!(
-        "listening on http://{}:{}",
-        local_addr.ip().bold(),
-        local_addr.port().bold()
-    );
+/// Walks `upload_dir` on the blocking pool to correct what the stats missed,
+/// unless the previous walk is still running, as a walk of a huge upload_dir
+/// can take longer than the interval. `None` when it was skipped.
+fn start_full_refresh(
+    state: &ArcState,
+    refreshing: &Arc<AtomicBool>,
+) -> Option<tokio::task::JoinHandle<()>> {
+    if refreshing.swap(true, Ordering::Relaxed) {
+        debug!("Previous stats refresh is still running, skipping");
+        return None;
+    }
+
+    debug!("Refreshing stats");
+    let state = state.clone();
+    let refreshing = refreshing.clone();
+    Some(tokio::spawn(async move {
+        let walk_state = state.clone();
+        match tokio::task::spawn_blocking(move || {
+            refresh_stats(&walk_state.config, |file_name| {
+                walk_state.uploads.read().unwrap().contains_key(file_name)
+            })
+        })
+        .await
+        {
+            Ok(Ok(stats)) => *state.stats_mut() = stats,
+            Ok(Err(err)) => error!("Error while refreshing stats: {}", err),
+            Err(err) => error!("Error while refreshing stats: {}", err),
+        }
+        refreshing.store(false, Ordering::Relaxed);
+    }))
+}
+
+async fn get_stats(State(state): State<ArcState>, headers: HeaderMap) -> Response {
+    let stats = state.stats().clone();
+
+    let mut response = match negotiate_format(&headers) {
+        Format::Html => render_html_table(&stats).into_response(),
+        Format::Json => Slonkable::from(stats).into_response(),
+        Format::Plain => render_plain(&stats).into_response(),
+    };
+
+    add_vary(response.headers_mut(), "accept");
+
+    // the stats are only allowed to be stale for stats_interval seconds
+    if let Ok(value) = HeaderValue::from_str(&stats_cache_control(state.config.stats_interval)) {
+        response.headers_mut().insert(CACHE_CONTROL, value);
+    }
+
+    response
+}
+
+async fn metrics(State(state): State<ArcState>) -> Result<Response, AppError> {
+    let Some(metrics) = &state.metrics else {
+        return Err(AppError::NotFound);
+    };
 
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(),
     )
-    .await
-    .unwrap();
+        .into_response())
+}
+
+async fn favicon(State(state): State<ArcState>) -> Result<Response, AppError> {
+    match &state.config.favicon {
+        Some(path) => {
+            let icon = tokio::fs::read(path).await?;
+            Ok((
+                [
+                    (CONTENT_TYPE, favicon_content_type(path)),
+                    (CACHE_CONTROL, FAVICON_CACHE_CONTROL),
+                ],
+                icon,
+            )
+                .into_response())
+        }
+        None => Ok((
+            StatusCode::NO_CONTENT,
+            [(CACHE_CONTROL, FAVICON_CACHE_CONTROL)],
+        )
+            .into_response()),
+    }
+}
+
+/// An SVG QR code of a stored file's URL, for opening it on a phone.
+async fn qr_code(
+    State(state): State<ArcState>,
+    Path(file_name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if file_name.contains('/') || file_name == "." || file_name == ".." {
+        return Err(AppError::NotFound);
+    }
+    if !tokio::fs::try_exists(state.config.file_path(&file_name)).await? {
+        return Err(AppError::NotFound);
+    }
+
+    // without public_url the URL is relative, which means nothing to a phone
+    let mut url = state.file_url(&file_name);
+    if state.config.public_url.is_none() {
+        let host = headers
+            .get(HOST)
+            .and_then(|v| v.to_str().ok())
+            .ok_or(AppError::BadRequest)?;
+        url = format!("http://{}{}", host, url);
+    }
+
+    let code = QrCode::new(url).map_err(|_| AppError::BadRequest)?;
+    let svg = code.render::<svg::Color>().min_dimensions(256, 256).build();
+
+    Ok((
+        [
+            (CONTENT_TYPE, "image/svg+xml"),
+            (CACHE_CONTROL, QR_CODE_CACHE_CONTROL),
+        ],
+        svg,
+    )
+        .into_response())
+}
+
+async fn upload(
+    State(state): State<ArcState>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    Path(path): Path<String>,
+    request: Request,
+) -> Result<Response, AppError> {
+    // the whole request is passed on so the body limit set on the route is too
+    if is_multipart(request.headers()) {
+        let headers = request.headers().clone();
+        let multipart = Multipart::from_request(request, &state)
+            .await
+            .map_err(|_| AppError::BadRequest)?;
+        return upload_form(State(state), ConnectInfo(peer), headers, multipart).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let headers = parts.headers;
+    let stored = store_upload(
+        &state,
+        &peer,
+        path,
+        &headers,
+        UploadLength::Declared,
+        body.into_data_stream(),
+    )
+    .await?;
+    Ok((
+        stored
+            .delete_token
+            .map(|delete_token| [(DELETE_TOKEN_HEADER, delete_token)]),
+        state.upload_response(&stored.file_name),
+    )
+        .into_response())
+}
+
+/// Deletes a file for whoever has its delete token, the one its upload was
+/// answered with.
+async fn delete_file(
+    State(state): State<ArcState>,
+    Path(file_name): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    if file_name.contains('/') || file_name == "." || file_name == ".." {
+        return Err(AppError::NotFound);
+    }
+    let file_name = state.config.normalize_name(&file_name).into_owned();
+
+    let token = headers
+        .get(DELETE_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .ok_or(AppError::Unauthorized)?;
+    let Some(expected) = Sidecar::load(&state.config, &file_name)
+        .await?
+        .and_then(|sidecar| sidecar.delete_token)
+    else {
+        return Err(AppError::NotFound);
+    };
+    if !constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+        return Err(AppError::Unauthorized);
+    }
+
+    let file_path = state.config.file_path(&file_name);
+    let size = match tokio::fs::metadata(&file_path).await {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(AppError::NotFound),
+        Err(err) => return Err(err.into()),
+    };
+    // someone else deleted it between the metadata and here
+    if !state.delete_stored(&file_name, &file_path, size).await? {
+        return Err(AppError::NotFound);
+    }
+    info!("deleted '{}' ({})", file_name, format_size(size, DECIMAL));
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stores every file of a `multipart/form-data` upload, as sent by an HTML
+/// form, under the names in their `Content-Disposition` and responds with the
+/// stored names and their delete tokens. Either every file is stored or none
+/// are.
+async fn upload_form(
+    State(state): State<ArcState>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response, AppError> {
+    // the headers describe the whole form, not the files inside it
+    let mut part_headers = headers;
+    for name in [
+        "content-length",
+        "content-type",
+        "content-encoding",
+        "idempotency-key",
+        "x-checksum-sha256",
+        "x-reservation-token",
+    ] {
+        part_headers.remove(name);
+    }
+
+    let mut uploads = Vec::new();
+    let result = async {
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|_| AppError::BadRequest)?
+        {
+            // plain form fields have no file name
+            let Some(name) = field.file_name().map(str::to_string) else {
+                continue;
+            };
+            // a part's size is only known once it was read
+            let stored = store_upload(
+                &state,
+                &peer,
+                name,
+                &part_headers,
+                UploadLength::Unknown,
+                field,
+            )
+            .await?;
+            uploads.push(FormUpload {
+                name: stored.file_name,
+                delete_token: stored.delete_token,
+            });
+        }
+        Ok(())
+    }
+    .await;
+
+    // the client never learns the names and delete tokens of the files stored
+    // before one failed, so they would be left behind for good
+    if let Err(err) = result {
+        for upload in &uploads {
+            let file_path = state.config.file_path(&upload.name);
+            let removed = match tokio::fs::metadata(&file_path).await {
+                Ok(metadata) => {
+                    state
+                        .delete_stored(&upload.name, &file_path, metadata.len())
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = removed {
+                error!("Error while removing '{}': {}", upload.name, err);
+            }
+        }
+        return Err(err);
+    }
+
+    if uploads.is_empty() {
+        return Err(AppError::BadRequest);
+    }
+
+    Ok(Slonkable::from(uploads).into_response())
+}
+
+/// Where the size of an upload is known from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UploadLength {
+    /// The request's `Content-Length`, as the body is the file.
+    Declared,
+    /// Nowhere until the file was read, like a file inside a form.
+    Unknown,
+}
+
+/// Stores an upload and returns what it was stored as. Everything
+/// logged while doing so is in an `upload` span, to tell concurrent uploads
+/// apart.
+async fn store_upload<E: Into<BoxError>>(
+    state: &ArcState,
+    peer: &PeerAddr,
+    path: String,
+    headers: &HeaderMap,
+    length: UploadLength,
+    body: impl Stream<Item = Result<Bytes, E>> + Send,
+) -> Result<Stored, AppError> {
+    let span = info_span!(
+        "upload",
+        ip = %state.client_ip(headers, peer),
+        file_name = field::Empty,
+        size = field::Empty,
+    );
+
+    let result = store_upload_in_span(state, peer, path, headers, length, body)
+        .instrument(span.clone())
+        .await;
+
+    span.in_scope(|| match &result {
+        Ok(_) => info!("upload finished"),
+        Err(err) => info!("upload failed: {}", err),
+    });
+    result
+}
+
+async fn store_upload_in_span<E: Into<BoxError>>(
+    state: &ArcState,
+    peer: &PeerAddr,
+    path: String,
+    headers: &HeaderMap,
+    length: UploadLength,
+    body: impl Stream<Item = Result<Bytes, E>> + Send,
+) -> Result<Stored, AppError> {
+    let identity = state.authenticate(headers, peer).await?;
+
+    if state.read_only.load(Ordering::Relaxed) {
+        return Err(AppError::StorageFull);
+    }
+
+    let _file_slot = state.reserve_file_slot()?;
+
+    // chunked uploads (curl from a pipe, fetch with a stream) have no length,
+    // their size is only known once the whole body was read. other uploads
+    // without one are only let through with require_content_length off
+    let chunked = headers
+        .get(TRANSFER_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"));
+    let content_length = headers
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let file_size = match (length, content_length) {
+        (UploadLength::Unknown, _) => None,
+        (UploadLength::Declared, Some(content_length)) => Some(content_length),
+        (UploadLength::Declared, None) if chunked || !state.config.require_content_length => None,
+        (UploadLength::Declared, None) => return Err(AppError::BadRequest),
+    };
+
+    if let Some(file_size) = file_size {
+        Span::current().record("size", file_size);
+    }
+
+    if file_size.is_some_and(|file_size| file_size > state.config.max_file_size) {
+        return Err(AppError::FileAboveMaxSize);
+    }
+
+    let ip = state.client_ip(headers, peer);
+
+    // claimed right away so concurrent uploads from the same IP can't all
+    // squeeze into what is left, and checked again as the upload is read
+    let mut quota = state.reserve_quota(ip, file_size)?;
+
+    let compressed = match headers.get("content-encoding").map(|value| {
+        value
+            .to_str()
+            .map(|value| value.trim().to_ascii_lowercase())
+    }) {
+        None => false,
+        Some(Ok(encoding)) if encoding == "identity" => false,
+        Some(Ok(encoding)) if encoding == "gzip" || encoding == "x-gzip" => true,
+        Some(_) => return Err(AppError::UnsupportedEncoding),
+    };
+
+    // a small gzip body can expand into something enormous, so the decompressed
+    // size is capped by both max_file_size and max_decompression_ratio
+    let max_size = match file_size {
+        Some(file_size) if compressed && state.config.max_decompression_ratio != 0 => state
+            .config
+            .max_file_size
+            .min(file_size.saturating_mul(state.config.max_decompression_ratio)),
+        _ => state.config.max_file_size,
+    };
+
+    let expected_checksum = match headers.get("x-checksum-sha256") {
+        Some(value) => match value.to_str().ok().and_then(parse_sha256) {
+            Some(checksum) => Some(checksum),
+            None => return Err(AppError::BadRequest),
+        },
+        None => None,
+    };
+
+    // only ever shortens how long a file is kept, never past file_ttl_secs
+    let expire_after = match headers.get("x-expire-after") {
+        Some(value) => match value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+        {
+            Some(secs) if state.config.file_ttl_secs.is_none_or(|ttl| secs <= ttl) => Some(secs),
+            _ => return Err(AppError::BadRequest),
+        },
+        None => None,
+    };
+
+    let idempotency_key = headers
+        .get("idempotency-key")
+        .and_then(|value| value.to_str().ok())
+        .map(|key| {
+            let scope = match &identity.name {
+                Some(name) => IdempotencyScope::Identity(name.clone()),
+                None => IdempotencyScope::Ip(ip),
+            };
+            (scope, key.to_string())
+        });
+
+    // the key is taken before anything is stored, so concurrent retries
+    // can't both store the file
+    let idempotency_guard = match idempotency_key {
+        Some(key) => {
+            let fingerprint = UploadFingerprint {
+                requested_name: path.clone(),
+                size: file_size,
+                checksum: expected_checksum.clone().ok_or(AppError::BadRequest)?,
+            };
+
+            let mut idempotency_keys = state.idempotency_keys.write().unwrap();
+            match idempotency_keys.get(&key) {
+                Some(previous) if previous.created.elapsed() < IDEMPOTENCY_KEY_TTL => {
+                    if previous.fingerprint != fingerprint {
+                        return Err(AppError::IdempotencyKeyReused);
+                    }
+                    let Some(file_name) = &previous.file_name else {
+                        return Err(AppError::IdempotencyKeyInUse);
+                    };
+                    debug!("replaying upload of '{}' for idempotency key", file_name);
+                    return Ok(Stored {
+                        file_name: file_name.clone(),
+                        delete_token: None,
+                    });
+                }
+                _ => {
+                    idempotency_keys.insert(
+                        key.clone(),
+                        IdempotentUpload {
+                            fingerprint,
+                            file_name: None,
+                            created: Instant::now(),
+                        },
+                    );
+                }
+            }
+
+            Some(IdempotencyGuard {
+                state: state.clone(),
+                key,
+            })
+        }
+        None => None,
+    };
+
+    let reservation_token = headers
+        .get("x-reservation-token")
+        .and_then(|value| value.to_str().ok());
+
+    // a reserved name was already generated, the file is stored under it as is
+    let file_name = match reservation_token {
+        Some(token) => match state
+            .reservations
+            .read()
+            .unwrap()
+            .get(&*state.config.normalize_name(&path))
+        {
+            Some(reservation) if reservation.expires > Instant::now() => {
+                if !constant_time_eq(token.as_bytes(), reservation.token.as_bytes()) {
+                    return Err(AppError::Unauthorized);
+                }
+                state.config.normalize_name(&path).into_owned()
+            }
+            _ => return Err(AppError::NotFound),
+        },
+        None => state.generate_file_name(&path)?,
+    };
+
+    Span::current().record("file_name", file_name.as_str());
+    let file_path = state.config.file_path(&file_name);
+
+    // `If-None-Match: *` asks for the upload to only create the file, which is
+    // all we do anyway, but it expects a 412 instead of a 409 if it exists
+    let exists_error = if headers
+        .get("if-none-match")
+        .is_some_and(|value| value.as_bytes() == b"*")
+    {
+        AppError::PreconditionFailed
+    } else {
+        AppError::Conflict
+    };
+
+    if tokio::fs::try_exists(&file_path).await? {
+        return Err(exists_error);
+    }
+
+    let cancel = CancellationToken::new();
+    {
+        let mut uploads = state.uploads.write().unwrap();
+        if uploads.contains_key(&file_name)
+            || (reservation_token.is_none() && state.is_reserved(&file_name))
+        {
+            return Err(exists_error);
+        }
+        uploads.insert(
+            file_name.clone(),
+            ActiveUpload {
+                requested_name: path.clone(),
+                cancel_token: headers
+                    .get(CANCEL_TOKEN_HEADER)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string),
+                cancel: cancel.clone(),
+            },
+        );
+    }
+    let _guard = UploadGuard {
+        state: state.clone(),
+        file_name: file_name.clone(),
+    };
+
+    let _permit = state.open_file_permit().await;
+
+    let mut delete_token = None;
+    if let Err(err) = async {
+        let uploaded_at = SystemTime::now();
+
+        info!(
+            "{} is uploading file {} ({})",
+            match &identity.name {
+                Some(name) => format!("{} ({})", state.client_name(headers, peer), name),
+                None => state.client_name(headers, peer),
+            },
+            file_name.if_supports_color(Stderr, |text| text.bold()),
+            match file_size {
+                Some(file_size) => format_size(file_size, DECIMAL),
+                None => "unknown size".to_string(),
+            },
+        );
+
+        if state.config.shard
+            && let Some(shard_dir) = file_path.parent()
+        {
+            tokio::fs::create_dir_all(shard_dir).await?;
+        }
+
+        let mut out_file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&file_path)
+            .await?;
+
+        if let Some(file_size) = file_size
+            && file_size > 0
+            && state.config.allocate
+            && !compressed
+        {
+            debug!(
+                "allocating {} for '{}'",
+                format_size(file_size, DECIMAL),
+                file_name
+            );
+
+            out_file.set_len(file_size).await?;
+        }
+
+        let mirror_path = state.config.mirror_path(&file_name);
+        let mut mirror = match &mirror_path {
+            Some(mirror_path) => match open_mirror(mirror_path).await {
+                Ok(mirror) => Some(mirror),
+                Err(err) => {
+                    state.mirror_failed(&file_name, err)?;
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let mut hasher =
+            (expected_checksum.is_some() || state.config.store_checksums).then(Sha256::new);
+
+        let body = StreamReader::new(body.map_err(|err| io::Error::other(err)));
+        let body: Pin<Box<dyn AsyncRead + Send + '_>> = if compressed {
+            Box::pin(GzipDecoder::new(body))
+        } else {
+            Box::pin(body)
+        };
+
+        // the limit is enforced on what is actually read, whatever the
+        // request claimed its size to be
+        let mut reader = LimitedReader::new(body, max_size);
+        let mut buf = vec![0; copy_buffer_size(file_size)];
+        let progress = AtomicU64::new(0);
+
+        let written = tokio::select! {
+            result = async {
+                // the start of the file is read ahead to tell what it is, then
+                // written like the rest of it
+                let mut head = Vec::new();
+                if state.config.mime_sniffing {
+                    (&mut reader).take(SNIFF_LENGTH).read_to_end(&mut head).await?;
+                    if let Some(kind) = infer::get(&head)
+                        && state.config.mime_blocked(kind.mime_type())
+                    {
+                        debug!("'{}' looks like {}, refusing it", file_name, kind.mime_type());
+                        return Err(AppError::BlockedContentType);
+                    }
+                }
+                let mut reader = io::Cursor::new(head).chain(&mut reader);
+
+                let mut written = 0;
+                loop {
+                    let read = reader.read(&mut buf).await?;
+                    if read == 0 {
+                        break;
+                    }
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(&buf[..read]);
+                    }
+                    out_file.write_all(&buf[..read]).await?;
+                    if let Some(mirror_file) = &mut mirror
+                        && let Err(err) = mirror_file.write_all(&buf[..read]).await
+                    {
+                        state.mirror_failed(&file_name, err)?;
+                        mirror = None;
+                    }
+                    written += read as u64;
+                    progress.store(written, Ordering::Relaxed);
+                    if let Some(quota) = quota.as_mut() {
+                        quota.grow(written)?;
+                    }
+                }
+                Ok::<_, AppError>(written)
+            } => match result {
+                Err(AppError::IoError(err)) if err.kind() == io::ErrorKind::FileTooLarge => {
+                    return Err(AppError::FileAboveMaxSize)
+                }
+                result => result?,
+            },
+            _ = cancel.cancelled() => return Err(AppError::Cancelled),
+            _ = too_slow(&state.config, &progress) => return Err(AppError::UploadTooSlow),
+        };
+
+        Span::current().record("size", written);
+
+        let checksum = hasher.map(|hasher| format!("{:x}", hasher.finalize()));
+        if let Some(expected) = &expected_checksum
+            && checksum.as_ref() != Some(expected)
+        {
+            return Err(AppError::ChecksumMismatch);
+        }
+
+        // the mtime is what listings, ETags and expiry go by, so pin it to
+        // the upload instead of whatever touches the file later
+        out_file.flush().await?;
+        set_file_mtime(&file_path, FileTime::from_system_time(uploaded_at))?;
+
+        if let Some(mirror_file) = &mut mirror
+            && let Some(mirror_path) = &mirror_path
+            && let Err(err) = async {
+                mirror_file.flush().await?;
+                set_file_mtime(mirror_path, FileTime::from_system_time(uploaded_at))?;
+                if state.config.sync_on_upload {
+                    mirror_file.sync_all().await?;
+                }
+                Ok::<_, io::Error>(())
+            }
+            .await
+        {
+            state.mirror_failed(&file_name, err)?;
+            mirror = None;
+        }
+        // a mirror that failed halfway is worse than none
+        if mirror.is_none()
+            && let Some(mirror_path) = &mirror_path
+            && let Err(err) = remove_file_if_exists(mirror_path).await
+        {
+            error!(
+                "Error while removing file '{}': {}",
+                mirror_path.display(),
+                err
+            );
+        }
+
+        let token = get_random_prefix(DELETE_TOKEN_LENGTH);
+        let sidecar = Sidecar {
+            original_name: if state.config.store_original_names {
+                Some(sanitize_file_name(
+                    &state.remove_control_chars(&path)?,
+                    state.config.name_policy,
+                ))
+            } else {
+                None
+            },
+            checksum: checksum.filter(|_| state.config.store_checksums),
+            expires_at: expire_after.map(|secs| {
+                (uploaded_at + Duration::from_secs(secs))
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |expires_at| expires_at.as_secs())
+            }),
+            delete_token: Some(token.clone()),
+        };
+        sidecar.save(&state.config, &file_name).await?;
+
+        if expire_after.is_some() && !state.reaper_started.load(Ordering::Relaxed) {
+            let marker_path = path::Path::new(&state.config.temp_dir).join(EXPIRING_MARKER);
+            if let Err(err) = tokio::fs::write(&marker_path, b"").await {
+                error!(
+                    "Error while writing file '{}': {}",
+                    marker_path.display(),
+                    err
+                );
+            }
+            start_reaper(state);
+        }
+
+        if state.config.sync_on_upload {
+            trace!("syncing '{}' to disk", file_name);
+            out_file.sync_all().await?;
+        }
+
+        delete_token = Some(token);
+
+        let mut stats = state.stats_mut();
+
+        stats.files_stored += 1;
+        stats.storage_used += written;
+        drop(stats);
+
+        state.record_upload(ip, written);
+
+        Ok::<_, AppError>(())
+    }
+    .await
+    {
+        if let AppError::IoError(io_err) = &err
+            && is_storage_full(io_err)
+        {
+            state.enter_read_only();
+        }
+
+        trace!("cleaning up failed upload of '{}'", file_name);
+        if let Err(err) = Sidecar::remove(&state.config, &file_name).await {
+            error!("Error while removing sidecar of '{}': {}", file_name, err);
+        }
+        if let Some(mirror_path) = state.config.mirror_path(&file_name)
+            && let Err(err) = remove_file_if_exists(&mirror_path).await
+        {
+            error!(
+                "Error while removing file '{}': {}",
+                mirror_path.display(),
+                err
+            );
+        }
+        match tokio::fs::try_exists(&file_path).await {
+            Ok(exists) => {
+                if exists {
+                    if let Err(err) = tokio::fs::remove_file(&file_path).await {
+                        error!(
+                            "Error while removing file '{}': {}",
+                            file_path.display(),
+                            err
+                        );
+                    };
+                }
+            }
+            Err(err) => {
+                error!(
+                    "Error while checking if file '{}' exists: {}",
+                    file_path.display(),
+                    err
+                )
+            }
+        };
+
+        Err(err)
+    } else {
+        if reservation_token.is_some() {
+            state.reservations.write().unwrap().remove(&file_name);
+        }
+
+        if let Some(guard) = &idempotency_guard
+            && let Some(upload) = state.idempotency_keys.write().unwrap().get_mut(&guard.key)
+        {
+            upload.file_name = Some(file_name.clone());
+            upload.created = Instant::now();
+        }
+
+        Ok(Stored {
+            file_name,
+            delete_token,
+        })
+    }
+}
+
+async fn activity(
+    State(state): State<ArcState>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    headers: HeaderMap,
+) -> Result<Slonkable<Vec<IpActivity>>, AppError> {
+    state.authorize_admin(&headers, &peer).await?;
+    state.prune_ip_uploads();
+
+    let mut activity: Vec<IpActivity> = state
+        .ip_uploads
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, uploads)| !uploads.finished.is_empty())
+        .map(|(ip, uploads)| IpActivity {
+            ip: *ip,
+            uploads: uploads.finished.len(),
+            bytes: uploads.finished.iter().map(|(_, size)| size).sum(),
+        })
+        .collect();
+    activity.sort_unstable_by_key(|activity| std::cmp::Reverse(activity.bytes));
+
+    Ok(activity.into())
+}
+
+async fn reserve(
+    State(state): State<ArcState>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    state.authenticate(&headers, &peer).await?;
+    let file_name = state.generate_file_name(&path)?;
+
+    let file_path = state.config.file_path(&file_name);
+    if tokio::fs::try_exists(&file_path).await? {
+        return Err(AppError::Conflict);
+    }
+
+    let token = get_random_prefix(32);
+    {
+        let mut reservations = state.reservations.write().unwrap();
+        if reservations
+            .get(&file_name)
+            .is_some_and(|reservation| reservation.expires > Instant::now())
+        {
+            return Err(AppError::Conflict);
+        }
+        reservations.insert(
+            file_name.clone(),
+            Reservation {
+                token: token.clone(),
+                expires: Instant::now() + Duration::from_secs(state.config.reservation_ttl),
+            },
+        );
+    }
+
+    info!("reserved file name '{}'", file_name);
+
+    Ok((
+        StatusCode::CREATED,
+        Slonkable::from(ReservationResponse {
+            url: state.file_url(&file_name),
+            name: file_name,
+            token,
+            expires_in: state.config.reservation_ttl,
+        }),
+    )
+        .into_response())
+}
+
+async fn validate(
+    State(state): State<ArcState>,
+    Query(query): Query<ValidateQuery>,
+) -> Result<Slonkable<ValidateResponse>, AppError> {
+    let control_chars = state.remove_control_chars(&query.name);
+    let sanitized = state
+        .config
+        .normalize_name(&sanitize_file_name(
+            control_chars.as_deref().unwrap_or(&query.name),
+            state.config.name_policy,
+        ))
+        .into_owned();
+
+    // the same checks an upload goes through, so the answer can't drift from
+    // what an upload would get
+    let reason = match state.generate_file_name(&query.name) {
+        Err(AppError::IoError(err)) => return Err(err.into()),
+        Err(err) => Some(err.to_string()),
+        // the random prefix makes collisions a non-issue
+        Ok(_) if state.config.prefix_length > 0 => None,
+        Ok(file_name)
+            if state.is_reserved(&file_name)
+                || tokio::fs::try_exists(state.config.file_path(&file_name)).await? =>
+        {
+            Some("File already exists".to_string())
+        }
+        Ok(_) => None,
+    };
+
+    Ok(Slonkable::from(ValidateResponse {
+        valid: reason.is_none(),
+        reason,
+        sanitized,
+    }))
+}
+
+async fn verify(
+    State(state): State<ArcState>,
+    Path(file_name): Path<String>,
+) -> Result<Slonkable<Verification>, AppError> {
+    if file_name.contains('/') || file_name == "." || file_name == ".." {
+        return Err(AppError::NotFound);
+    }
+
+    let file_path = state.config.file_path(&file_name);
+    if !tokio::fs::try_exists(&file_path).await? {
+        return Err(AppError::NotFound);
+    }
+
+    // without a checksum there is nothing to verify, and no reason to read
+    // the whole file
+    let Some(expected) = Sidecar::load(&state.config, &state.config.normalize_name(&file_name))
+        .await?
+        .and_then(|sidecar| sidecar.checksum)
+    else {
+        return Err(AppError::NotFound);
+    };
+
+    // hashing reads the whole file, so only a few run at once, and they count
+    // against max_open_files too
+    let _verify_permit = state.verify_permits.acquire().await.unwrap();
+    let _permit = state.open_file_permit().await;
+    let actual = hash_file(file_path).await?;
+
+    if expected != actual {
+        warn!("'{}' does not match its stored checksum", file_name);
+    }
+
+    Ok(Slonkable::from(Verification {
+        ok: expected == actual,
+        expected,
+        actual,
+    }))
+}
+
+async fn cancel_upload(
+    State(state): State<ArcState>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    state.authenticate(&headers, &peer).await?;
+
+    // names are easy to guess, even with the random prefix when it is
+    // disabled, so only the secret the uploader sent along cancels an upload
+    let Some(cancel_token) = headers
+        .get(CANCEL_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Err(AppError::NotFound);
+    };
+    let stored_name = state.config.normalize_name(&name);
+
+    let uploads = state.uploads.read().unwrap();
+    let mut found = false;
+
+    for (file_name, upload) in uploads.iter() {
+        let token_matches = upload
+            .cancel_token
+            .as_ref()
+            .is_some_and(|expected| constant_time_eq(cancel_token.as_bytes(), expected.as_bytes()));
+        if (*file_name == stored_name || upload.requested_name == name) && token_matches {
+            info!("cancelling upload of '{}'", file_name);
+            upload.cancel.cancel();
+            found = true;
+        }
+    }
+
+    if found {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound)
+    }
+}
+
+async fn export(
+    State(state): State<ArcState>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    state.authorize_admin(&headers, &peer).await?;
+
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    let export_state = state.clone();
+
+    let task = tokio::spawn(async move {
+        let state = export_state;
+        let mut builder = tokio_tar::Builder::new(writer);
+
+        let result = async {
+            // shards are flattened, the archive holds files by their logical name
+            let mut dirs = vec![path::PathBuf::from(&state.config.upload_dir)];
+            while let Some(dir) = dirs.pop() {
+                let mut files_dir = tokio::fs::read_dir(&dir).await?;
+                while let Some(file) = files_dir.next_entry().await? {
+                    let file_type = file.file_type().await?;
+                    if file_type.is_file() {
+                        // still being written, and zero-filled past what was
+                        // received when it was preallocated
+                        let file_name = file.file_name().to_string_lossy().into_owned();
+                        if state.uploads.read().unwrap().contains_key(&file_name) {
+                            continue;
+                        }
+                        let _permit = state.open_file_permit().await;
+                        builder
+                            .append_path_with_name(file.path(), file.file_name())
+                            .await?;
+                    } else if state.config.shard && file_type.is_dir() {
+                        dirs.push(file.path());
+                    }
+                }
+            }
+            builder.finish().await
+        }
+        .await;
+
+        if let Err(err) = &result {
+            error!("Error while exporting uploads: {}", err);
+        }
+        result
+    });
+
+    let file_name = chrono::Utc::now().format("bingus-files_%Y-%m-%dT%H-%M-%SZ.tar");
+    let accept_encoding = headers.get(ACCEPT_ENCODING).and_then(|v| v.to_str().ok());
+
+    let mut response = Response::builder()
+        .header(CONTENT_TYPE, "application/x-tar")
+        .header(
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        );
+
+    let reader: Pin<Box<dyn AsyncRead + Send>> =
+        match preferred_encoding(accept_encoding, &["gzip"]) {
+            Some(encoding) => {
+                response = response.header(CONTENT_ENCODING, encoding);
+                Box::pin(GzipEncoder::new(BufReader::new(reader)))
+            }
+            None => Box::pin(reader),
+        };
+
+    // the archive just ends when writing it failed, failing the body after it
+    // aborts the response so it can't be mistaken for a complete one
+    let failure = futures::stream::once(async move {
+        match task.await {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(Err(err)),
+            Err(err) => Some(Err(io::Error::other(err))),
+        }
+    })
+    .filter_map(std::future::ready);
+
+    let mut response = response
+        .body(Body::from_stream(ReaderStream::new(reader).chain(failure)))
+        .unwrap();
+    add_vary(response.headers_mut(), "accept-encoding");
+
+    Ok(response)
+}
+
+async fn limit_open_files(State(state): State<ArcState>, request: Request, next: Next) -> Response {
+    let Some(permit) = state.open_file_permit().await else {
+        return next.run(request).await;
+    };
+
+    // the file stays open while the body is streamed, so the permit has to
+    // live as long as the body does
+    next.run(request).await.map(|body| {
+        Body::from_stream(body.into_data_stream().inspect(move |_| {
+            let _ = &permit;
+        }))
+    })
+}
+
+async fn etag(State(state): State<ArcState>, request: Request, next: Next) -> Response {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return next.run(request).await;
+    }
+
+    let etag = match state.served_file(request.uri()).await {
+        Some((path, metadata)) => state.etag(path, &metadata).await,
+        None => None,
+    };
+    let Some(etag) = etag else {
+        return next.run(request).await;
+    };
+    let if_none_match = request.headers().get(IF_NONE_MATCH).cloned();
+
+    // the response is needed even for a 304, its ETag has to be the one a
+    // 200 would have had, which depends on how it was encoded
+    let mut response = next.run(request).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    // a strong validator is only for these exact bytes, which differ between
+    // the plain and a compressed response
+    let etag = if response.headers().contains_key(CONTENT_ENCODING) && !etag.starts_with("W/") {
+        format!("W/{}", etag)
+    } else {
+        etag
+    };
+    let Ok(etag) = HeaderValue::from_str(&etag) else {
+        return response;
+    };
+
+    if let Some(if_none_match) = if_none_match.as_ref().and_then(|value| value.to_str().ok())
+        && etag_matches(if_none_match, etag.to_str().unwrap_or_default())
+    {
+        let mut not_modified = (StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response();
+        for name in [CACHE_CONTROL, VARY] {
+            if let Some(value) = response.headers().get(&name) {
+                not_modified.headers_mut().insert(name, value.clone());
+            }
+        }
+        return not_modified;
+    }
+
+    response.headers_mut().insert(ETAG, etag);
+    response
+}
+
+/// Offers the name a file was uploaded as when it is downloaded.
+async fn original_name(State(state): State<ArcState>, request: Request, next: Next) -> Response {
+    if !state.config.store_original_names
+        || (request.method() != Method::GET && request.method() != Method::HEAD)
+    {
+        return next.run(request).await;
+    }
+
+    let original_name = match state.served_file(request.uri()).await {
+        Some((path, _)) if path.starts_with(&state.config.upload_dir) => {
+            match path.file_name().and_then(|name| name.to_str()) {
+                Some(file_name) => match Sidecar::load(&state.config, file_name).await {
+                    Ok(sidecar) => sidecar.and_then(|sidecar| sidecar.original_name),
+                    Err(err) => {
+                        warn!("Error while reading sidecar of '{}': {}", file_name, err);
+                        None
+                    }
+                },
+                None => None,
+            }
+        }
+        _ => None,
+    };
+
+    let mut response = next.run(request).await;
+    if let Some(original_name) = original_name
+        && response.status().is_success()
+        && let Ok(value) = HeaderValue::from_str(&content_disposition("inline", &original_name))
+    {
+        response.headers_mut().insert(CONTENT_DISPOSITION, value);
+    }
+    response
+}
+
+async fn set_maintenance(
+    State(state): State<ArcState>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    state.authorize_admin(&headers, &peer).await?;
+
+    let enabled = method == Method::PUT;
+    if state.maintenance.swap(enabled, Ordering::Relaxed) != enabled {
+        info!(
+            "maintenance mode {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Runs the expiry sweep right away.
+async fn expire(
+    State(state): State<ArcState>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    headers: HeaderMap,
+) -> Result<Slonkable<Expired>, AppError> {
+    state.authorize_admin(&headers, &peer).await?;
+    Ok(state.expire_files().await?.into())
+}
+
+/// Refuses writes while in maintenance mode, and serves the maintenance page
+/// in place of the index if there is one.
+async fn maintenance(State(state): State<ArcState>, request: Request, next: Next) -> Response {
+    if !state.maintenance.load(Ordering::Relaxed) || request.uri().path() == "/api/v1/maintenance" {
+        return next.run(request).await;
+    }
+
+    let retry_after = [(
+        RETRY_AFTER,
+        state.config.maintenance_retry_after.to_string(),
+    )];
+    let method = request.method();
+
+    if method != Method::GET && method != Method::HEAD && method != Method::OPTIONS {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            retry_after,
+            "Down for maintenance",
+        )
+            .into_response();
+    }
+
+    if let Some(page) = &state.config.maintenance_page
+        && matches!(request.uri().path(), "/" | "/index.html")
+    {
+        return match tokio::fs::read(page).await {
+            Ok(page) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                retry_after,
+                [(CONTENT_TYPE, "text/html; charset=utf-8")],
+                page,
+            )
+                .into_response(),
+            Err(err) => AppError::from(err).into_response(),
+        };
+    }
+
+    next.run(request).await
+}
+
+fn parse_security_headers(headers: &HashMap<String, String>) -> HeaderMap {
+    let mut parsed = HeaderMap::new();
+    for (name, value) in headers {
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                parsed.insert(name, value);
+            }
+            _ => warn!("invalid security header '{}: {}'", name, value),
+        }
+    }
+    parsed
+}
+
+async fn security_headers(State(state): State<ArcState>, request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+
+    // handlers that set one of these know better
+    for (name, value) in &state.security_headers {
+        if !response.headers().contains_key(name) {
+            response.headers_mut().insert(name, value.clone());
+        }
+    }
+
+    response
+}
+
+async fn preload_headers(State(state): State<ArcState>, request: Request, next: Next) -> Response {
+    let links = state.config.preload.get(request.uri().path());
+    let mut response = next.run(request).await;
+
+    if let Some(links) = links
+        && response.status().is_success()
+    {
+        for link in links {
+            match HeaderValue::from_str(link) {
+                Ok(value) => {
+                    response.headers_mut().append(LINK, value);
+                }
+                Err(err) => warn!("invalid preload header '{}': {}", link, err),
+            }
+        }
+    }
+
+    response
+}
+
+async fn method_policy(State(state): State<ArcState>, request: Request, next: Next) -> Response {
+    let allowed = &state.config.allowed_methods;
+    if allowed
+        .iter()
+        .any(|method| method.as_str() == request.method().as_str())
+    {
+        return next.run(request).await;
+    }
+
+    debug!("rejecting {} request", request.method());
+    let mut response = silly!(METHOD_NOT_ALLOWED).into_response();
+    if let Ok(value) = HeaderValue::from_str(&allowed.join(", ")) {
+        response.headers_mut().insert(ALLOW, value);
+    }
+    response
+}
+
+async fn logger(
+    State(state): State<ArcState>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = state.client_name(request.headers(), &peer);
+
+    let path = request.uri().path().to_owned();
+    let method = request.method().to_owned();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed();
+
+    let status_code = response.status().as_u16();
+
+    if let Some(metrics) = &state.metrics {
+        metrics.observe(method.as_str(), status_code, elapsed);
+    }
+
+    // favicons are requested on every page load and busy instances may sample
+    // successful requests, keep those out of the access log
+    if path == "/favicon.ico" || !should_log(status_code, state.config.logging.sample_rate) {
+        trace!(
+            "({}) {} {} {} ({:#?})",
+            ip,
+            status_code,
+            method,
+            path,
+            elapsed
+        );
+        return response;
+    }
+
+    info!(
+        "({}) {} {} {} ({:#?})",
+        ip,
+        status_code.if_supports_color(Stderr, |text| text
+            .style(color_status_code(status_code).bold())),
+        method.if_supports_color(Stderr, |text| text.bold()),
+        path,
+        elapsed
+    );
+
+    response
+}
+
+/// Builds the state shared by every request, creating `upload_dir` and
+/// `temp_dir` if they don't exist yet.
+async fn app_state(config: Config) -> ArcState {
+    if !try_exists(&config.upload_dir).await.unwrap() {
+        debug!("Creating upload directory");
+        tokio::fs::create_dir_all(&config.upload_dir).await.unwrap();
+    }
+    if !try_exists(&config.temp_dir).await.unwrap() {
+        debug!("Creating temp directory");
+        tokio::fs::create_dir_all(&config.temp_dir).await.unwrap();
+    }
+
+    let stats = refresh_stats(&config, |_| false).unwrap();
+
+    Arc::new(AppState {
+        config: config.clone(),
+        stats: RwLock::new(stats),
+        pending_files: AtomicU64::new(0),
+        uploads: RwLock::new(HashMap::new()),
+        open_files: (config.max_open_files != 0)
+            .then(|| Arc::new(Semaphore::new(config.max_open_files))),
+        ip_uploads: RwLock::new(HashMap::new()),
+        idempotency_keys: RwLock::new(HashMap::new()),
+        reservations: RwLock::new(HashMap::new()),
+        etag_cache: RwLock::new(HashMap::new()),
+        authenticator: if config.upload_tokens.is_empty() {
+            Box::new(Anonymous)
+        } else {
+            Box::new(StaticTokens::new(config.upload_tokens.clone()))
+        },
+        read_only: AtomicBool::new(false),
+        metrics: config.metrics.then(Metrics::default),
+        security_headers: parse_security_headers(&config.security_headers),
+        maintenance: AtomicBool::new(config.maintenance),
+        auth_failures: RwLock::new(HashMap::new()),
+        verify_permits: Semaphore::new(MAX_CONCURRENT_VERIFICATIONS),
+        reaper_started: AtomicBool::new(false),
+    })
+}
+
+/// Routes requests to the handlers, behind the middleware and concurrency
+/// limit the config asks for.
+fn app(state: ArcState) -> Router {
+    let config = &state.config;
+    let serve_files = ServeDir::new(&config.upload_dir).precompressed_gzip();
+    let stored_config = config.clone();
+    let serve_files = serve_files.map_request(move |mut request: Request| {
+        if let Some(uri) = stored_uri(&stored_config, request.uri()) {
+            *request.uri_mut() = uri;
+        }
+        request
+    });
+    let serve_static =
+        Compression::new(ServeDir::new(path::Path::new(STATIC_DIR)).fallback(serve_files));
+
+    let app = Router::new()
+        .nest_service(
+            "/",
+            get_service(serve_static)
+                .layer(from_fn_with_state(state.clone(), limit_open_files))
+                .layer(from_fn_with_state(state.clone(), preload_headers))
+                .layer(from_fn_with_state(state.clone(), etag))
+                .layer(from_fn_with_state(state.clone(), original_name))
+                .fallback_service(
+                    Router::new()
+                        .route(
+                            "/:file",
+                            put(upload).delete(delete_file).with_state(state.clone()),
+                        )
+                        // sizes are checked per file as the upload is read
+                        .layer(DefaultBodyLimit::disable()),
+                ),
+        )
+        .route("/stats", get(get_stats))
+        .route("/favicon.ico", get(favicon))
+        .route("/upload/:file", delete(cancel_upload))
+        // kept off the top level, where they would shadow files of the same name
+        .nest(
+            "/api/v1",
+            api::router(config)
+                .route("/metrics", get(metrics))
+                .route("/export.tar", get(export))
+                .route("/activity", get(activity))
+                .route("/reserve/:file", post(reserve))
+                .route("/verify/:file", get(verify))
+                .route("/qr/:file", get(qr_code))
+                .route("/maintenance", put(set_maintenance).delete(set_maintenance))
+                .route("/expire", post(expire))
+                .route("/validate", get(validate)),
+        )
+        .layer(from_fn_with_state(state.clone(), maintenance))
+        .layer(from_fn_with_state(state.clone(), security_headers))
+        .layer(from_fn_with_state(state.clone(), method_policy))
+        .layer(from_fn_with_state(state.clone(), logger))
+        .with_state(state.clone());
+    if config.http.concurrency_limit != 0 && config.http.shed_load {
+        let retry_after = config.http.shed_retry_after;
+        app.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(move |_: BoxError| async move {
+                    (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        [(RETRY_AFTER, jittered_retry_after(retry_after).to_string())],
+                        "Server is overloaded",
+                    )
+                }))
+                .load_shed()
+                .concurrency_limit(config.http.concurrency_limit),
+        )
+    } else if config.http.concurrency_limit != 0 {
+        app.layer(ConcurrencyLimitLayer::new(config.http.concurrency_limit))
+    } else {
+        app
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let config = match config::load().await {
+        Ok(config) => {
+            eprintln!("Loaded configuration from {}", config.1.display());
+            config.0
+        }
+        Err(ConfigError::NotFound) => {
+            eprintln!("No configuration file found, using default configuration");
+            Config::default()
+        }
+        Err(ConfigError::IoError(error)) => {
+            eprintln!("Error reading configuration: {}", error);
+            eprintln!("Check that the file exists and is readable, or unset BINGUS_CONFIG");
+            process::exit(1);
+        }
+        Err(ConfigError::ParseError(error)) => {
+            eprintln!("Error parsing configuration: {}", error);
+            eprintln!("See the README for an example configuration file");
+            process::exit(1);
+        }
+        Err(
+            error @ (ConfigError::InvalidPrefixFormat
+            | ConfigError::UnsafePrefixFormat
+            | ConfigError::InvalidMethod(_)),
+        ) => {
+            eprintln!("Invalid configuration: {}", error);
+            process::exit(1);
+        }
+    };
+
+    set_max_json_response_size(config.max_json_response_size);
+
+    tracing_subscriber::registry()
+        .with(LevelFilter::from_str(&config.logging.level).unwrap())
+        .with(
+            config
+                .logging
+                .stderr
+                .then_some(tracing_subscriber::fmt::layer()),
+        )
+        .with(
+            match &config.logging.file {
+                FileEnum::Boolean(value) => value.then_some(DEFAULT_LOG_PATH),
+                FileEnum::Path(value) => Some(value.as_str()),
+            }
+            .map(|path| {
+                let time = chrono::Utc::now();
+                let path = time.format(path).to_string();
+                let file = std::fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .unwrap();
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_writer(file)
+                    .with_ansi(false)
+            }),
+        )
+        .init();
+
+    debug!("{:#?}", &config);
+
+    let state = app_state(config.clone()).await;
+    let app = app(state.clone());
+
+    if config.file_ttl_secs.is_some()
+        || tokio::fs::try_exists(path::Path::new(&config.temp_dir).join(EXPIRING_MARKER))
+            .await
+            .unwrap_or(false)
+    {
+        start_reaper(&state);
+    }
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        let mut last_full_refresh = Instant::now();
+        let refreshing = Arc::new(AtomicBool::new(false));
+        loop {
+            sleep(Duration::from_secs(state.config.stats_interval)).await;
+            // uploads keep the stats up to date, walking everything is only
+            // needed to catch what changed behind our back
+            let full_refresh_interval = state.config.full_refresh_interval;
+            if full_refresh_interval != 0
+                && last_full_refresh.elapsed() >= Duration::from_secs(full_refresh_interval)
+                && start_full_refresh(&state, &refreshing).is_some()
+            {
+                last_full_refresh = Instant::now();
+            }
+            state.prune_ip_uploads();
+            state.prune_idempotency_keys();
+            state.prune_reservations();
+            state.prune_auth_failures();
+            state.check_read_only();
+        }
+    });
+
+    let accept_limiter = (config.http.accept_rate != 0)
+        .then(|| AcceptLimiter::new(config.http.accept_rate, config.http.accept_burst));
+
+    #[cfg(unix)]
+    if let Some(socket_path) = &config.http.unix_socket {
+        use std::os::unix::fs::FileTypeExt;
+
+        // a socket left behind by a previous run would make binding fail
+        if let Ok(metadata) = tokio::fs::symlink_metadata(socket_path).await
+            && metadata.file_type().is_socket()
+        {
+            debug!("removing stale socket {}", socket_path);
+            tokio::fs::remove_file(socket_path).await.unwrap();
+        }
+
+        let listener = UnixListener::bind(socket_path).unwrap();
+        info!("listening on unix:{}", socket_path.bold());
+        serve_connections(listener, app, accept_limiter, config.http.proxy_protocol).await;
+        return;
+    }
+
+    let address = (config.http.host.as_str(), config.http.port);
+    let listener = TcpListener::bind(address).await.unwrap();
+    let local_addr = listener.local_addr().unwrap();
+    info!(
+        "listening on http://{}:{}",
+        local_addr.ip().bold(),
+        local_addr.port().bold()
+    );
+
+    serve_connections(listener, app, accept_limiter, config.http.proxy_protocol).await;
+}
+
+/// A listener connections can be accepted from, along with who is connecting.
+trait Listener {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    async fn accept_stream(&self) -> io::Result<(Self::Stream, PeerAddr)>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept_stream(&self) -> io::Result<(Self::Stream, PeerAddr)> {
+        let (stream, addr) = self.accept().await?;
+        Ok((stream, PeerAddr::Tcp(addr)))
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept_stream(&self) -> io::Result<(Self::Stream, PeerAddr)> {
+        let (stream, _) = self.accept().await?;
+        Ok((stream, PeerAddr::Unix))
+    }
+}
+
+/// `axum::serve` only takes TCP listeners and has no say over how fast
+/// connections are accepted, so connections are handed to hyper by hand.
+async fn serve_connections<L: Listener>(
+    listener: L,
+    app: Router,
+    mut accept_limiter: Option<AcceptLimiter>,
+    proxy_protocol: bool,
+) {
+    use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+    use hyper_util::rt::TokioIo;
+
+    let make_service = app.into_make_service_with_connect_info::<PeerAddr>();
+
+    loop {
+        // connections over the limit wait in the listen backlog
+        if let Some(accept_limiter) = accept_limiter.as_mut() {
+            accept_limiter.acquire().await;
+        }
+
+        let (mut socket, peer) = match listener.accept_stream().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("Error while accepting connection: {}", err);
+                // back off so running out of file descriptors doesn't spin
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let mut make_service = make_service.clone();
+        tokio::spawn(async move {
+            let peer = if proxy_protocol {
+                match timeout(PROXY_HEADER_TIMEOUT, proxy::read_header(&mut socket)).await {
+                    Ok(Ok(Some(addr))) => PeerAddr::Tcp(addr),
+                    Ok(Ok(None)) => peer,
+                    Ok(Err(err)) => {
+                        debug!("Dropping connection from {:?}: {}", peer, err);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("Dropping connection from {:?}: no PROXY header", peer);
+                        return;
+                    }
+                }
+            } else {
+                peer
+            };
+
+            let service = match make_service.call(peer).await {
+                Ok(service) => service,
+                Err(err) => match err {},
+            };
+            let hyper_service = service_fn(move |request: hyper::Request<Incoming>| {
+                service.clone().oneshot(request)
+            });
+
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(socket), hyper_service)
+                .with_upgrades()
+                .await
+            {
+                debug!("Error while serving connection: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::connect_info::MockConnectInfo;
+    use futures::stream;
+    use tower::ServiceExt;
+
+    /// The app over its own upload_dir and temp_dir, removed again on drop.
+    struct TestApp {
+        app: Router,
+        state: ArcState,
+        dir: path::PathBuf,
+    }
+
+    impl TestApp {
+        async fn new(configure: impl FnOnce(&mut Config)) -> Self {
+            Self::with_state(configure, |_| {}).await
+        }
+
+        /// Like `new`, with a chance to change the state built from the config.
+        async fn with_state(
+            configure: impl FnOnce(&mut Config),
+            change: impl FnOnce(&mut AppState),
+        ) -> Self {
+            let dir = std::env::temp_dir().join(format!("bingus-files-{}", get_random_prefix(16)));
+            let mut config = Config {
+                upload_dir: dir.join("files").to_string_lossy().into_owned(),
+                temp_dir: dir.join("temp").to_string_lossy().into_owned(),
+                prefix_length: 0,
+                ..Config::default()
+            };
+            configure(&mut config);
+
+            let mut state = app_state(config).await;
+            change(Arc::get_mut(&mut state).unwrap());
+            let app = app(state.clone()).layer(MockConnectInfo(PeerAddr::Tcp(SocketAddr::from((
+                Ipv4Addr::LOCALHOST,
+                4040,
+            )))));
+            Self { app, state, dir }
+        }
+
+        async fn send(&self, request: Request) -> Response {
+            self.app.clone().oneshot(request).await.unwrap()
+        }
+
+        async fn put(&self, path: &str, content: &'static str) -> Response {
+            self.send(
+                Request::put(path)
+                    .header("content-length", content.len())
+                    .body(Body::from(content))
+                    .unwrap(),
+            )
+            .await
+        }
+
+        async fn get(&self, path: &str) -> Response {
+            self.send(Request::get(path).body(Body::empty()).unwrap())
+                .await
+        }
+    }
+
+    impl Drop for TestApp {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_double_extensions() {
+        let app = TestApp::new(|config| config.reject_double_extension = true).await;
+
+        assert_eq!(
+            app.put("/a.jpg.exe", "x").await.status(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(app.get("/a.jpg.exe").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            app.put("/a.min.js", "x").await.status(),
+            StatusCode::CREATED
+        );
+    }
+
+    /// A body that sends a few bytes of the ones it promises, then stalls.
+    fn stalled_put(path: &str) -> Request {
+        let body = stream::once(async { Ok::<_, io::Error>(Bytes::from_static(b"hello")) })
+            .chain(stream::pending());
+        Request::put(path)
+            .header("content-length", 100_000)
+            .body(Body::from_stream(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sheds_load_with_retry_after() {
+        let app = TestApp::new(|config| {
+            config.http.concurrency_limit = 1;
+            config.http.shed_load = true;
+        })
+        .await;
+
+        let stalled = tokio::spawn(app.app.clone().oneshot(stalled_put("/a.txt")));
+        sleep(Duration::from_millis(100)).await;
+
+        let response = app.get("/b.txt").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let retry_after: u64 = response.headers()[RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((5..=10).contains(&retry_after));
+        stalled.abort();
+    }
+
+    #[tokio::test]
+    async fn varies_stats_on_accept() {
+        let app = TestApp::new(|_| {}).await;
+
+        for accept in ["text/html", "application/json", "text/plain"] {
+            let response = app
+                .send(
+                    Request::get("/stats")
+                        .header("accept", accept)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.headers()[CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .starts_with(accept));
+            assert!(response.headers()[VARY]
+                .to_str()
+                .unwrap()
+                .eq_ignore_ascii_case("accept"));
+        }
+    }
+
+    fn expire_request(token: &str) -> Request {
+        Request::post("/api/v1/expire")
+            .header("authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn expires_files_past_ttl() {
+        let app = TestApp::new(|config| {
+            config.admin_token = Some("hunter2".into());
+            config.file_ttl_secs = Some(3600);
+        })
+        .await;
+
+        assert_eq!(
+            app.put("/a.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            app.put("/b.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+        let two_hours_ago = SystemTime::now() - Duration::from_secs(7200);
+        set_file_mtime(
+            app.dir.join("files/a.txt"),
+            FileTime::from_system_time(two_hours_ago),
+        )
+        .unwrap();
+
+        assert_eq!(
+            app.send(expire_request("wrong")).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            app.send(expire_request("hunter2")).await.status(),
+            StatusCode::OK
+        );
+
+        assert_eq!(app.get("/a.txt").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(app.get("/b.txt").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_uploads() {
+        let app = TestApp::new(|config| {
+            config.min_upload_rate = 1000;
+            config.min_upload_rate_grace = 0;
+        })
+        .await;
+
+        let response = timeout(Duration::from_secs(10), app.send(stalled_put("/a.txt")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(app.get("/a.txt").await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn expires_files_on_request() {
+        let app = TestApp::new(|config| {
+            config.admin_token = Some("hunter2".into());
+            config.file_ttl_secs = Some(3600);
+        })
+        .await;
+
+        let put_expiring = |expire_after: u64| {
+            Request::put("/a.txt")
+                .header("content-length", 5)
+                .header("x-expire-after", expire_after)
+                .body(Body::from("hello"))
+                .unwrap()
+        };
+        assert_eq!(
+            app.send(put_expiring(7200)).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            app.send(put_expiring(0)).await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            app.put("/b.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+
+        assert_eq!(
+            app.send(expire_request("hunter2")).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(app.get("/a.txt").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(app.get("/b.txt").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn deletes_with_token() {
+        let app = TestApp::new(|_| {}).await;
+
+        let response = app.put("/a.txt", "hello").await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let token = response.headers()[DELETE_TOKEN_HEADER].clone();
+
+        let response = app.get("/a.txt").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(content, "hello");
+
+        let delete_with = |token: HeaderValue| {
+            Request::delete("/a.txt")
+                .header(DELETE_TOKEN_HEADER, token)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(
+            app.send(delete_with(HeaderValue::from_static("wrong")))
+                .await
+                .status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            app.send(delete_with(token.clone())).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(app.get("/a.txt").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            app.send(delete_with(token)).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn cancels_uploads_with_token() {
+        let app = TestApp::new(|_| {}).await;
+
+        let mut upload = stalled_put("/a.txt");
+        upload
+            .headers_mut()
+            .insert(CANCEL_TOKEN_HEADER, HeaderValue::from_static("secret"));
+        let mut upload = tokio::spawn(app.app.clone().oneshot(upload));
+        sleep(Duration::from_millis(100)).await;
+
+        let cancel = |token: Option<&'static str>| {
+            let mut request = Request::delete("/upload/a.txt");
+            if let Some(token) = token {
+                request = request.header(CANCEL_TOKEN_HEADER, token);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        // the name alone doesn't let a stranger abort it
+        for token in [None, Some("guess")] {
+            assert_eq!(
+                app.send(cancel(token)).await.status(),
+                StatusCode::NOT_FOUND
+            );
+        }
+        assert!(timeout(Duration::from_millis(100), &mut upload)
+            .await
+            .is_err());
+
+        assert_eq!(
+            app.send(cancel(Some("secret"))).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        let response = timeout(Duration::from_secs(5), upload)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(app.get("/a.txt").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            app.send(cancel(Some("secret"))).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_favicon() {
+        let app = TestApp::new(|_| {}).await;
+        let response = app.get("/favicon.ico").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[CACHE_CONTROL], FAVICON_CACHE_CONTROL);
+
+        let app = TestApp::new(|config| {
+            config.favicon = Some(format!("{}/favicon.png", config.temp_dir));
+        })
+        .await;
+        std::fs::write(app.dir.join("temp/favicon.png"), b"\x89PNG").unwrap();
+        let response = app.get("/favicon.ico").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+        assert_eq!(response.headers()[CACHE_CONTROL], FAVICON_CACHE_CONTROL);
+        let icon = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(icon, &b"\x89PNG"[..]);
+    }
+
+    #[tokio::test]
+    async fn exports_tar() {
+        let app = TestApp::new(|config| config.admin_token = Some("hunter2".into())).await;
+        assert_eq!(
+            app.put("/a.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            app.put("/b.txt", "hello world").await.status(),
+            StatusCode::CREATED
+        );
+        let stalled = tokio::spawn(app.app.clone().oneshot(stalled_put("/c.txt")));
+        sleep(Duration::from_millis(100)).await;
+
+        let response = app
+            .send(
+                Request::get("/api/v1/export.tar")
+                    .header("authorization", "Bearer hunter2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/x-tar");
+        let tar = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        let mut entries = Vec::new();
+        let mut archive = tokio_tar::Archive::new(&tar[..]);
+        let mut archive_entries = archive.entries().unwrap();
+        while let Some(entry) = archive_entries.next().await {
+            let entry = entry.unwrap();
+            entries.push((
+                entry.path().unwrap().to_string_lossy().into_owned(),
+                entry.header().size().unwrap(),
+            ));
+        }
+        entries.sort();
+        assert_eq!(
+            entries,
+            [("a.txt".to_string(), 5), ("b.txt".to_string(), 11)]
+        );
+        stalled.abort();
+    }
+
+    #[tokio::test]
+    async fn stores_files_in_shards() {
+        let app = TestApp::new(|config| config.shard = true).await;
+
+        assert_eq!(
+            app.put("/abc.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+        assert!(app.dir.join("files/ab/abc.txt").is_file());
+        assert!(!app.dir.join("files/abc.txt").exists());
+
+        let response = app.get("/abc.txt").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(content, "hello");
+    }
+
+    #[tokio::test]
+    async fn stores_large_bodies_intact() {
+        let app = TestApp::new(|_| {}).await;
+
+        let content: Vec<u8> = (0..5_000_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        // uneven chunks, so they don't line up with the copy buffer
+        let chunks: Vec<Result<Bytes, io::Error>> = content
+            .chunks(12_345)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let response = app
+            .send(
+                Request::put("/large.bin")
+                    .header("content-length", content.len())
+                    .body(Body::from_stream(stream::iter(chunks)))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app.get("/large.bin").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let stored = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(stored == content);
+    }
+
+    #[tokio::test]
+    async fn rejects_disallowed_methods() {
+        let app = TestApp::new(|_| {}).await;
+
+        let response = app
+            .send(
+                Request::builder()
+                    .method("TRACE")
+                    .uri("/stats")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            response.headers()[ALLOW],
+            "GET, HEAD, PUT, POST, DELETE, OPTIONS"
+        );
+    }
+
+    /// Lets requests from localhost with the right API key write.
+    #[derive(Debug)]
+    struct ApiKey;
+
+    #[async_trait::async_trait]
+    impl Authenticator for ApiKey {
+        async fn authenticate(
+            &self,
+            headers: &HeaderMap,
+            ip: IpAddr,
+        ) -> Result<Identity, auth::AuthError> {
+            let key = headers.get("x-api-key").ok_or(auth::AuthError::Missing)?;
+            if key != "good" || !ip.is_loopback() {
+                return Err(auth::AuthError::Invalid);
+            }
+            Ok(Identity {
+                name: Some("integration".to_string()),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn uses_custom_authenticator() {
+        let app = TestApp::with_state(|_| {}, |state| state.authenticator = Box::new(ApiKey)).await;
+
+        let put_with_key = |key: Option<&'static str>| {
+            let mut request = Request::put("/a.txt").header("content-length", 5);
+            if let Some(key) = key {
+                request = request.header("x-api-key", key);
+            }
+            request.body(Body::from("hello")).unwrap()
+        };
+        assert_eq!(
+            app.send(put_with_key(None)).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            app.send(put_with_key(Some("bad"))).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(app.get("/a.txt").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            app.send(put_with_key(Some("good"))).await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(app.get("/a.txt").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn sends_security_headers() {
+        let app = TestApp::new(|_| {}).await;
+        assert_eq!(
+            app.put("/a.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+
+        let response = app.get("/a.txt").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+        // uploaded files can still be embedded elsewhere
+        assert!(!response.headers().contains_key("x-frame-options"));
+    }
+
+    #[tokio::test]
+    async fn stores_files_named_like_routes() {
+        let app = TestApp::new(|config| config.admin_token = Some("hunter2".into())).await;
+
+        for name in [
+            "maintenance",
+            "expire",
+            "activity",
+            "validate",
+            "metrics",
+            "reserve",
+        ] {
+            let path = format!("/{}", name);
+            assert_eq!(app.put(&path, "hello").await.status(), StatusCode::CREATED);
+            assert_eq!(app.get(&path).await.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_uploads_during_maintenance() {
+        let app = TestApp::new(|config| config.admin_token = Some("hunter2".into())).await;
+        assert_eq!(
+            app.put("/a.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+
+        let toggle = |method: Method| {
+            Request::builder()
+                .method(method)
+                .uri("/api/v1/maintenance")
+                .header("authorization", "Bearer hunter2")
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert!(app.send(toggle(Method::PUT)).await.status().is_success());
+
+        let response = app.put("/b.txt", "hello").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+        assert_eq!(app.get("/a.txt").await.status(), StatusCode::OK);
+
+        assert!(app.send(toggle(Method::DELETE)).await.status().is_success());
+        assert_eq!(
+            app.put("/b.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+    }
+
+    #[tokio::test]
+    async fn verifies_stored_checksums() {
+        let app = TestApp::new(|config| config.store_checksums = true).await;
+        assert_eq!(
+            app.put("/a.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+
+        let verify = async |name: &str| {
+            let response = app.get(&format!("/api/v1/verify/{}", name)).await;
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).ok(),
+            )
+        };
+
+        let (status, verification) = verify("a.txt").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(verification.unwrap()["ok"], true);
+
+        std::fs::write(app.dir.join("files/a.txt"), "hellp").unwrap();
+        let (status, verification) = verify("a.txt").await;
+        assert_eq!(status, StatusCode::OK);
+        let verification = verification.unwrap();
+        assert_eq!(verification["ok"], false);
+        assert_ne!(verification["expected"], verification["actual"]);
+
+        // stored behind our back, so there is no checksum to compare with
+        std::fs::write(app.dir.join("files/b.txt"), "hello").unwrap();
+        assert_eq!(verify("b.txt").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn keeps_stats_in_line_with_full_refresh() {
+        let app = TestApp::new(|_| {}).await;
+
+        assert_eq!(
+            app.put("/a.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+        let response = app.put("/b.txt", "hello world").await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let token = response.headers()[DELETE_TOKEN_HEADER].clone();
+        assert_eq!(app.put("/c.txt", "bye").await.status(), StatusCode::CREATED);
+        let response = app
+            .send(
+                Request::delete("/b.txt")
+                    .header(DELETE_TOKEN_HEADER, token)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        // preallocated on disk, but not stored yet
+        let stalled = tokio::spawn(app.app.clone().oneshot(stalled_put("/d.txt")));
+        sleep(Duration::from_millis(100)).await;
+
+        let refreshed = refresh_stats(&app.state.config, |file_name| {
+            app.state.uploads.read().unwrap().contains_key(file_name)
+        })
+        .unwrap();
+        let stats = app.state.stats().clone();
+        assert_eq!(
+            (stats.files_stored, stats.storage_used),
+            (refreshed.files_stored, refreshed.storage_used)
+        );
+        assert_eq!((stats.files_stored, stats.storage_used), (2, 8));
+        stalled.abort();
+    }
+
+    #[tokio::test]
+    async fn skips_overlapping_full_refreshes() {
+        let app = TestApp::new(|_| {}).await;
+        let refreshing = Arc::new(AtomicBool::new(false));
+
+        let walk = start_full_refresh(&app.state, &refreshing).unwrap();
+        // the first walk can't be done before this task yields
+        assert!(start_full_refresh(&app.state, &refreshing).is_none());
+        walk.await.unwrap();
+
+        assert!(!refreshing.load(Ordering::Relaxed));
+        start_full_refresh(&app.state, &refreshing)
+            .unwrap()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn tarpits_repeated_auth_failures() {
+        let app = TestApp::new(|config| {
+            config.upload_tokens = HashMap::from([("alice".to_string(), "hunter2".to_string())]);
+            config.auth_failure_threshold = 2;
+            config.auth_failure_delay = 200;
+        })
+        .await;
+
+        let put_with_token = |token: &str| {
+            Request::put("/a.txt")
+                .header("authorization", format!("Bearer {}", token))
+                .header("content-length", 5)
+                .body(Body::from("hello"))
+                .unwrap()
+        };
+        let timed = async |token: &str| {
+            let start = Instant::now();
+            let status = app.send(put_with_token(token)).await.status();
+            (status, start.elapsed())
+        };
+
+        for _ in 0..2 {
+            let (status, elapsed) = timed("guess").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert!(elapsed < Duration::from_millis(100));
+        }
+        for expected in [200, 400] {
+            let (status, elapsed) = timed("guess").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert!(elapsed >= Duration::from_millis(expected));
+        }
+
+        // the right token still works, and starts the count over
+        assert_eq!(timed("hunter2").await.0, StatusCode::CREATED);
+        let (status, elapsed) = timed("guess").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(elapsed < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn pages_through_files_by_size() {
+        let app = TestApp::new(|config| config.admin_token = Some("hunter2".into())).await;
+        for (name, content) in [
+            ("c", "ccc"),
+            ("a", "a"),
+            ("e", "eeeee"),
+            ("b", "bb"),
+            ("d", "dddd"),
+        ] {
+            assert_eq!(
+                app.put(&format!("/{}.txt", name), content).await.status(),
+                StatusCode::CREATED
+            );
+        }
+
+        let list = |query: String, token: &str| {
+            Request::get(format!("/api/v1/files?{}", query))
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(
+            app.send(list(String::new(), "wrong")).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let mut pages = Vec::new();
+        let mut query = "sort=size&order=desc&limit=2".to_string();
+        loop {
+            let response = app.send(list(query.clone(), "hunter2")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            pages.push(
+                page["files"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|file| {
+                        (
+                            file["name"].as_str().unwrap().to_string(),
+                            file["size"].as_u64().unwrap(),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            );
+            match page["next"].as_str() {
+                Some(next) => query = format!("sort=size&order=desc&limit=2&cursor={}", next),
+                None => break,
+            }
+        }
+
+        let page = |files: &[(&str, u64)]| {
+            files
+                .iter()
+                .map(|(name, size)| (name.to_string(), *size))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            pages,
+            [
+                page(&[("e.txt", 5), ("d.txt", 4)]),
+                page(&[("c.txt", 3), ("b.txt", 2)]),
+                page(&[("a.txt", 1)]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn mirrors_uploads() {
+        let app = TestApp::new(|config| {
+            let mirror_dir = path::Path::new(&config.upload_dir).with_file_name("mirror");
+            config.mirror_dir = Some(mirror_dir.to_string_lossy().into_owned());
+        })
+        .await;
+
+        let response = app.put("/a.txt", "hello mirror").await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let stored = std::fs::read(app.dir.join("files/a.txt")).unwrap();
+        let mirrored = std::fs::read(app.dir.join("mirror/a.txt")).unwrap();
+        assert_eq!(stored, b"hello mirror");
+        assert_eq!(mirrored, stored);
+
+        let response = app
+            .send(
+                Request::delete("/a.txt")
+                    .header(
+                        DELETE_TOKEN_HEADER,
+                        response.headers()[DELETE_TOKEN_HEADER].clone(),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!app.dir.join("mirror/a.txt").exists());
+    }
+
+    fn form(files: &[(&str, &str)]) -> Request {
+        let mut body = String::new();
+        for (name, content) in files {
+            body += &format!(
+                "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n{}\r\n",
+                name, content
+            );
+        }
+        body += "--boundary--\r\n";
+        Request::put("/form")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+            .header("content-length", body.len())
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn stores_form_uploads() {
+        let app = TestApp::new(|config| config.reject_double_extension = true).await;
+
+        let response = app
+            .send(form(&[("a.txt", "hello"), ("b.txt", "world")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let stored: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stored[0]["name"], "a.txt");
+        assert_eq!(stored[1]["name"], "b.txt");
+        assert!(stored[0]["delete_token"].is_string());
+        assert_eq!(app.get("/b.txt").await.status(), StatusCode::OK);
+        assert_eq!(app.get("/form").await.status(), StatusCode::NOT_FOUND);
+
+        // the file stored before the rejected one is removed again
+        let response = app
+            .send(form(&[("c.txt", "hello"), ("d.jpg.exe", "world")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(app.get("/c.txt").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(app.state.stats().files_stored, 2);
+
+        // there is no route in the way of a file called upload
+        assert_eq!(
+            app.put("/upload", "hello").await.status(),
+            StatusCode::CREATED
+        );
+    }
+
+    /// An `upload` span as it was when it closed, with what was logged in it.
+    #[derive(Debug, Default)]
+    struct CapturedSpan {
+        fields: HashMap<String, String>,
+        messages: Vec<String>,
+    }
+
+    /// Captures every `upload` span.
+    #[derive(Clone, Default)]
+    struct CaptureSpans(Arc<std::sync::Mutex<Vec<CapturedSpan>>>);
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl field::Visit for FieldVisitor<'_> {
+        fn record_str(&mut self, field: &field::Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl<S> tracing_subscriber::Layer<S> for CaptureSpans
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if attrs.metadata().name() != "upload" {
+                return;
+            }
+            let mut captured = CapturedSpan::default();
+            attrs.record(&mut FieldVisitor(&mut captured.fields));
+            ctx.span(id).unwrap().extensions_mut().insert(captured);
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let span = ctx.span(id).unwrap();
+            let mut extensions = span.extensions_mut();
+            if let Some(captured) = extensions.get_mut::<CapturedSpan>() {
+                values.record(&mut FieldVisitor(&mut captured.fields));
+            }
+        }
+
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let Some(span) = ctx.event_span(event) else {
+                return;
+            };
+            let mut extensions = span.extensions_mut();
+            if let Some(captured) = extensions.get_mut::<CapturedSpan>() {
+                let mut fields = HashMap::new();
+                event.record(&mut FieldVisitor(&mut fields));
+                captured.messages.extend(fields.remove("message"));
+            }
+        }
+
+        fn on_close(&self, id: tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            let captured = span.extensions_mut().remove::<CapturedSpan>();
+            self.0.lock().unwrap().extend(captured);
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_uploads_in_span() {
+        let captured = CaptureSpans::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(captured.clone()));
+        let app = TestApp::new(|config| config.reject_double_extension = true).await;
+
+        assert_eq!(
+            app.put("/a.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            app.put("/b.jpg.exe", "hello").await.status(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        drop(app);
+
+        let spans = captured.0.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        let fields = |span: &CapturedSpan| {
+            ["ip", "file_name", "size"].map(|name| span.fields.get(name).cloned())
+        };
+        assert_eq!(
+            fields(&spans[0]),
+            [
+                Some("127.0.0.1".to_string()),
+                Some("a.txt".to_string()),
+                Some("5".to_string())
+            ]
+        );
+        assert!(spans[0]
+            .messages
+            .iter()
+            .any(|message| message == "upload finished"));
+        assert_eq!(spans[1].fields["ip"], "127.0.0.1");
+        assert!(spans[1]
+            .messages
+            .iter()
+            .any(|message| message.starts_with("upload failed")));
+    }
+
+    #[tokio::test]
+    async fn refuses_sniffed_mime_types() {
+        let app = TestApp::new(|config| {
+            config.mime_sniffing = true;
+            config.blocked_mime = vec![
+                "application/x-executable".to_string(),
+                "image/*".to_string(),
+            ];
+        })
+        .await;
+
+        let put_bytes = |path: &str, content: Vec<u8>| {
+            Request::put(path)
+                .header("content-length", content.len())
+                .body(Body::from(content))
+                .unwrap()
+        };
+        let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+        elf.resize(64, 0);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.resize(64, 0);
+
+        // whatever they are called
+        for (name, content) in [("/a.txt", elf), ("/b.txt", png)] {
+            assert_eq!(
+                app.send(put_bytes(name, content)).await.status(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            );
+            assert_eq!(app.get(name).await.status(), StatusCode::NOT_FOUND);
+        }
+        assert_eq!(
+            app.send(put_bytes("/c.txt", b"just text".to_vec()))
+                .await
+                .status(),
+            StatusCode::CREATED
+        );
+    }
+
+    #[tokio::test]
+    async fn deleting_twice_only_counts_once() {
+        let app = TestApp::new(|_| {}).await;
+        assert_eq!(
+            app.put("/a.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            app.put("/b.txt", "world").await.status(),
+            StatusCode::CREATED
+        );
+
+        let file_path = app.state.config.file_path("a.txt");
+        assert!(app
+            .state
+            .delete_stored("a.txt", &file_path, 5)
+            .await
+            .unwrap());
+        assert!(!app
+            .state
+            .delete_stored("a.txt", &file_path, 5)
+            .await
+            .unwrap());
+
+        let stats = app.state.stats();
+        assert_eq!((stats.files_stored, stats.storage_used), (1, 5));
+    }
+
+    #[tokio::test]
+    async fn keeps_delete_tokens_in_sidecars() {
+        let app = TestApp::new(|_| {}).await;
+
+        let response = app.put("/a.txt", "hello").await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let token = response.headers()[DELETE_TOKEN_HEADER].to_str().unwrap();
+
+        let sidecar = Sidecar::load(&app.state.config, "a.txt").await.unwrap();
+        assert_eq!(
+            sidecar.and_then(|sidecar| sidecar.delete_token).as_deref(),
+            Some(token)
+        );
+    }
+
+    #[tokio::test]
+    async fn starts_reaper_on_first_expiring_upload() {
+        let app = TestApp::new(|config| config.reap_interval = 1).await;
+
+        assert_eq!(
+            app.put("/a.txt", "hello").await.status(),
+            StatusCode::CREATED
+        );
+        assert!(!app.state.reaper_started.load(Ordering::Relaxed));
+
+        let request = Request::put("/b.txt")
+            .header("content-length", 5)
+            .header("x-expire-after", 0)
+            .body(Body::from("hello"))
+            .unwrap();
+        assert_eq!(app.send(request).await.status(), StatusCode::CREATED);
+        assert!(app.state.reaper_started.load(Ordering::Relaxed));
+        assert!(app.dir.join("temp").join(EXPIRING_MARKER).exists());
+
+        sleep(Duration::from_millis(1500)).await;
+        assert_eq!(app.get("/a.txt").await.status(), StatusCode::OK);
+        assert_eq!(app.get("/b.txt").await.status(), StatusCode::NOT_FOUND);
+    }
 }
@@ -0,0 +1,51 @@
+use std::{io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// What is known about a stored file besides its contents, kept next to it in
+/// `temp_dir` as `<name>.meta`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sidecar {
+    /// The sanitized name the file was uploaded as, before any prefix.
+    pub original_name: Option<String>,
+    /// Hex SHA-256 of the contents as they were uploaded.
+    pub checksum: Option<String>,
+    /// Unix time after which the uploader wants the file deleted.
+    pub expires_at: Option<u64>,
+    /// The token the uploader can delete the file with. Every stored file gets
+    /// its own, so a token can't outlive the file it was for and end up
+    /// deleting whatever is stored under the same name next.
+    pub delete_token: Option<String>,
+}
+
+fn sidecar_path(config: &Config, file_name: &str) -> PathBuf {
+    PathBuf::from(&config.temp_dir).join(format!("{}.meta", file_name))
+}
+
+impl Sidecar {
+    /// Reads the sidecar of a file, `None` if it has none.
+    pub async fn load(config: &Config, file_name: &str) -> io::Result<Option<Self>> {
+        match tokio::fs::read(sidecar_path(config, file_name)).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn save(&self, config: &Config, file_name: &str) -> io::Result<()> {
+        tokio::fs::create_dir_all(&config.temp_dir).await?;
+        tokio::fs::write(sidecar_path(config, file_name), serde_json::to_vec(self)?).await
+    }
+
+    pub async fn remove(config: &Config, file_name: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(sidecar_path(config, file_name)).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+/// Upper bounds of the latency buckets in seconds, `+Inf` is implied.
+const BUCKETS: [f64; 4] = [0.001, 0.01, 0.1, 1.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, the last one being `+Inf`. Not cumulative,
+    /// that is done when rendering.
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+/// Request latency histograms by method and status class.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    durations: Mutex<BTreeMap<(&'static str, &'static str), Histogram>>,
+}
+
+/// Methods get a label of their own, anything else a client makes up is
+/// `other` so it can't create new series without bound.
+fn method_label(method: &str) -> &'static str {
+    const METHODS: [&str; 9] = [
+        "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+    ];
+    METHODS
+        .into_iter()
+        .find(|known| *known == method)
+        .unwrap_or("other")
+}
+
+fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+impl Metrics {
+    pub fn observe(&self, method: &str, status: u16, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|le| seconds <= *le)
+            .unwrap_or(BUCKETS.len());
+
+        let mut durations = self.durations.lock().unwrap();
+        let histogram = durations
+            .entry((method_label(method), status_class(status)))
+            .or_default();
+        histogram.counts[bucket] += 1;
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP bingus_request_duration_seconds Time taken to handle requests.\n");
+        out.push_str("# TYPE bingus_request_duration_seconds histogram\n");
+
+        for ((method, status), histogram) in self.durations.lock().unwrap().iter() {
+            let labels = format!("method=\"{}\",status=\"{}\"", method, status);
+
+            let mut cumulative = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                cumulative += count;
+                let le = BUCKETS
+                    .get(i)
+                    .map_or("+Inf".to_string(), |le| le.to_string());
+                let _ = writeln!(
+                    out,
+                    "bingus_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "bingus_request_duration_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "bingus_request_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn observes_into_buckets() {
+        let metrics = Metrics::default();
+        metrics.observe("GET", 200, Duration::from_millis(5));
+        metrics.observe("GET", 204, Duration::from_millis(50));
+
+        let rendered = metrics.render();
+        for line in [
+            "bingus_request_duration_seconds_bucket{method=\"GET\",status=\"2xx\",le=\"0.001\"} 0",
+            "bingus_request_duration_seconds_bucket{method=\"GET\",status=\"2xx\",le=\"0.01\"} 1",
+            "bingus_request_duration_seconds_bucket{method=\"GET\",status=\"2xx\",le=\"0.1\"} 2",
+            "bingus_request_duration_seconds_bucket{method=\"GET\",status=\"2xx\",le=\"+Inf\"} 2",
+            "bingus_request_duration_seconds_count{method=\"GET\",status=\"2xx\"} 2",
+        ] {
+            assert!(
+                rendered.lines().any(|rendered| rendered == line),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn bounds_method_labels() {
+        let metrics = Metrics::default();
+        for method in ["FOO", "BAR", "get", "PUT"] {
+            metrics.observe(method, 405, Duration::ZERO);
+        }
+
+        let durations = metrics.durations.lock().unwrap();
+        assert_eq!(
+            durations.keys().copied().collect::<Vec<_>>(),
+            [("PUT", "4xx"), ("other", "4xx")]
+        );
+        assert_eq!(durations[&("other", "4xx")].count, 3);
+    }
+}
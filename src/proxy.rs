@@ -0,0 +1,204 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest a v1 header can be, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("PROXY protocol: {}", message),
+    )
+}
+
+/// Reads a PROXY protocol (v1 or v2) header off the start of a connection,
+/// without reading any further. Returns the address of the client the proxy
+/// is forwarding for, `None` for connections the proxy made by itself (health
+/// checks) or didn't know the addresses of.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        let mut addresses = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(header[0], header[1], &addresses)
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(invalid("v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(std::str::from_utf8(&line).map_err(|_| invalid("v1 header isn't ASCII"))?)
+    } else {
+        Err(invalid("missing header"))
+    }
+}
+
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let mut parts = line.trim_end_matches("\r\n").split(' ').skip(1);
+
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unknown v1 protocol")),
+    }
+
+    let source: IpAddr = parts
+        .next()
+        .and_then(|ip| ip.parse().ok())
+        .ok_or_else(|| invalid("bad v1 source address"))?;
+    let _destination = parts.next();
+    let port: u16 = parts
+        .next()
+        .and_then(|port| port.parse().ok())
+        .ok_or_else(|| invalid("bad v1 source port"))?;
+
+    Ok(Some(SocketAddr::new(source, port)))
+}
+
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+
+    match version_command & 0x0f {
+        // LOCAL, the proxy talking to us on its own behalf
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unknown v2 command")),
+    }
+
+    // the high nibble is the address family, the low one TCP or UDP
+    match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x1 | 0x2 => Err(invalid("v2 addresses too short")),
+        // AF_UNSPEC or AF_UNIX, nothing useful to go by
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_header(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([version_command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[test]
+    fn parses_v1() {
+        assert_eq!(
+            parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+        assert_eq!(parse_v1("PROXY UNKNOWN\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_bad_v1() {
+        assert!(parse_v1("PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n").is_err());
+        assert!(parse_v1("PROXY TCP4 not-an-ip 198.51.100.1 56324 443\r\n").is_err());
+        assert!(parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n").is_err());
+        assert!(parse_v1("PROXY TCP4 192.0.2.1\r\n").is_err());
+    }
+
+    #[test]
+    fn parses_v2() {
+        let mut ipv4 = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        ipv4.extend(56324u16.to_be_bytes());
+        ipv4.extend(443u16.to_be_bytes());
+        assert_eq!(
+            parse_v2(0x21, 0x11, &ipv4).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+
+        let mut ipv6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        ipv6.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend(56324u16.to_be_bytes());
+        ipv6.extend(443u16.to_be_bytes());
+        assert_eq!(
+            parse_v2(0x21, 0x21, &ipv6).unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+
+        // LOCAL and AF_UNSPEC have no client address
+        assert_eq!(parse_v2(0x20, 0x00, &[]).unwrap(), None);
+        assert_eq!(parse_v2(0x21, 0x00, &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_bad_v2() {
+        assert!(parse_v2(0x11, 0x11, &[0; 12]).is_err());
+        assert!(parse_v2(0x22, 0x11, &[0; 12]).is_err());
+        // truncated address blocks
+        assert!(parse_v2(0x21, 0x11, &[0; 11]).is_err());
+        assert!(parse_v2(0x21, 0x21, &[0; 35]).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_headers_off_a_stream() {
+        let mut stream: &[u8] =
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        // nothing past the header is read
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addresses.extend(56324u16.to_be_bytes());
+        addresses.extend(443u16.to_be_bytes());
+        let mut header = v2_header(0x21, 0x11, &addresses);
+        header.extend(b"GET");
+        let mut stream = &header[..];
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET");
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_headers_on_a_stream() {
+        // the length says there are more addresses than were sent
+        let mut header = v2_header(0x21, 0x11, &[0; 12]);
+        header.truncate(header.len() - 4);
+        assert!(read_header(&mut &header[..]).await.is_err());
+
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LENGTH));
+        let err = read_header(&mut long.as_bytes()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        assert!(read_header(&mut &b"GET / HTTP/1.1\r\n\r\n"[..])
+            .await
+            .is_err());
+    }
+}
@@ -1,9 +1,60 @@
+use crate::config::NamePolicy;
 use axum::{
-    http::HeaderMap,
-    response::{IntoResponse, Response},
+    http::{
+        header::{CONTENT_TYPE, VARY},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{Html, IntoResponse, Response},
 };
 use owo_colors::Style;
 use rand::Rng;
+use std::{
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::{sleep, Instant},
+};
+use tracing::error;
+
+#[cfg(unix)]
+const ENOSPC: i32 = 28;
+
+/// `max_json_response_size`, set once at startup since responses don't get
+/// to see the config.
+static MAX_JSON_RESPONSE_SIZE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+pub fn set_max_json_response_size(size: usize) {
+    MAX_JSON_RESPONSE_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// Collects serialized JSON, failing as soon as it would grow past `cap`
+/// instead of after the whole thing was allocated.
+struct CappedWriter {
+    buf: Vec<u8>,
+    cap: usize,
+}
+
+impl io::Write for CappedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len().saturating_add(data.len()) > self.cap {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                "JSON response is above max_json_response_size",
+            ));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
 pub struct Slonkable<T: serde::Serialize>(T);
 impl<T: serde::Serialize> From<T> for Slonkable<T> {
@@ -13,19 +64,409 @@ impl<T: serde::Serialize> From<T> for Slonkable<T> {
 }
 impl<T: serde::Serialize> IntoResponse for Slonkable<T> {
     fn into_response(self) -> Response {
-        serde_json::to_string(&self.0)
-            .map_err(|x| x.to_string())
+        let mut writer = CappedWriter {
+            buf: Vec::new(),
+            cap: MAX_JSON_RESPONSE_SIZE.load(Ordering::Relaxed),
+        };
+        serde_json::to_writer(&mut writer, &self.0)
+            .map(|()| ([(CONTENT_TYPE, "application/json")], writer.buf))
+            .map_err(|x| {
+                error!("Error while serializing response: {}", x);
+                (StatusCode::INTERNAL_SERVER_ERROR, x.to_string())
+            })
             .into_response()
     }
 }
 
-pub fn sanitize_file_name(name: &str) -> String {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Html,
+    Json,
+    Plain,
+}
+
+/// Parses a list header like `Accept` or `Accept-Encoding` into lowercased
+/// values and their q-values, most preferred first. Values with the same
+/// q-value keep the order they were sent in.
+pub fn parse_q_values(header: &str) -> Vec<(String, f32)> {
+    let mut values: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let value = params.next()?.trim().to_ascii_lowercase();
+            if value.is_empty() {
+                return None;
+            }
+            let q = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            Some((value, q.clamp(0.0, 1.0)))
+        })
+        .collect();
+    values.sort_by(|a, b| b.1.total_cmp(&a.1));
+    values
+}
+
+/// Picks a content coding from `Accept-Encoding` out of `supported`, which is
+/// in our order of preference. `None` means the response should be sent as is.
+pub fn preferred_encoding(
+    accept_encoding: Option<&str>,
+    supported: &[&'static str],
+) -> Option<&'static str> {
+    let preferences = parse_q_values(accept_encoding?);
+    let q_of = |encoding: &str| {
+        preferences
+            .iter()
+            .find(|(value, _)| value == encoding || (encoding == "gzip" && value == "x-gzip"))
+            .or_else(|| preferences.iter().find(|(value, _)| value == "*"))
+            .map(|(_, q)| *q)
+    };
+
+    // identity is acceptable unless it is explicitly ruled out
+    let identity_q = q_of("identity").unwrap_or(1.0);
+    let (encoding, q) = supported
+        .iter()
+        .map(|encoding| (*encoding, q_of(encoding).unwrap_or(0.0)))
+        .fold(
+            None,
+            |best: Option<(&'static str, f32)>, candidate| match best {
+                Some(best) if best.1 >= candidate.1 => Some(best),
+                _ => Some(candidate),
+            },
+        )?;
+
+    (q > 0.0 && q >= identity_q).then_some(encoding)
+}
+
+pub fn is_multipart(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.trim_start()
+                .to_ascii_lowercase()
+                .starts_with("multipart/form-data")
+        })
+}
+
+/// Adds a header name to `Vary`, keeping the ones already there, so caches
+/// know the response was picked based on it.
+pub fn add_vary(headers: &mut HeaderMap, name: &str) {
+    let existing = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+
+    if existing
+        .iter()
+        .any(|value| value == "*" || value.eq_ignore_ascii_case(name))
+    {
+        return;
+    }
+
+    let vary = existing
+        .into_iter()
+        .chain([name.to_string()])
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(value) = HeaderValue::from_str(&vary) {
+        headers.insert(VARY, value);
+    }
+}
+
+/// Picks a response format from the `Accept` header. Only an explicit HTML or
+/// plain text type switches away from JSON, as that is what API clients (which
+/// send no header or `*/*`) got before negotiation existed.
+pub fn negotiate_format(headers: &HeaderMap) -> Format {
+    let Some(accept) = headers.get("accept").and_then(|v| v.to_str().ok()) else {
+        return Format::Json;
+    };
+
+    for (media_type, q) in parse_q_values(accept) {
+        if q == 0.0 {
+            continue;
+        }
+        match media_type.as_str() {
+            "text/html" | "application/xhtml+xml" => return Format::Html,
+            "application/json" => return Format::Json,
+            "text/plain" => return Format::Plain,
+            _ => {}
+        }
+    }
+
+    Format::Json
+}
+
+fn fields<T: serde::Serialize>(value: &T) -> Vec<(String, String)> {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::Object(map)) => map
+            .into_iter()
+            .map(|(key, value)| match value {
+                serde_json::Value::String(string) => (key, string),
+                value => (key, value.to_string()),
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn render_html_table<T: serde::Serialize>(value: &T) -> Html<String> {
+    let mut html = String::from("<!DOCTYPE html>\n<table>\n");
+    for (key, value) in fields(value) {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td></tr>\n",
+            escape_html(&key),
+            escape_html(&value)
+        ));
+    }
+    html.push_str("</table>\n");
+    Html(html)
+}
+
+pub fn render_plain<T: serde::Serialize>(value: &T) -> String {
+    fields(value)
+        .into_iter()
+        .map(|(key, value)| format!("{}: {}\n", key, value))
+        .collect()
+}
+
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// ASCII control characters can truncate paths or hide parts of a name.
+pub fn has_control_chars(name: &str) -> bool {
+    name.chars().any(|c| c.is_ascii_control())
+}
+
+pub fn strip_control_chars(name: &str) -> String {
+    name.replace(|c: char| c.is_ascii_control(), "")
+}
+
+/// Extensions that get run rather than opened, what a name like
+/// `photo.jpg.exe` is trying to sneak past someone.
+const RISKY_EXTENSIONS: &[&str] = &[
+    "exe", "scr", "com", "bat", "cmd", "pif", "msi", "vbs", "vbe", "js", "jse", "wsf", "wsh",
+    "hta", "ps1", "jar", "lnk", "sh",
+];
+
+/// Whether a name has more than one extension and the last one is risky,
+/// unless the last two are one of the `benign` pairs (like `min.js`).
+pub fn has_suspicious_double_extension(name: &str, benign: &[String]) -> bool {
+    let parts: Vec<&str> = name.trim_start_matches('.').rsplitn(3, '.').collect();
+    let [last, second_last, _] = parts[..] else {
+        return false;
+    };
+    if second_last.is_empty() || !RISKY_EXTENSIONS.contains(&last.to_lowercase().as_str()) {
+        return false;
+    }
+
+    let pair = format!("{}.{}", second_last, last);
+    !benign
+        .iter()
+        .any(|benign| benign.eq_ignore_ascii_case(&pair))
+}
+
+pub fn sanitize_file_name(name: &str, policy: NamePolicy) -> String {
+    match policy {
+        NamePolicy::Posix => sanitize_posix(name),
+        NamePolicy::Windows => sanitize_windows(&sanitize_posix(name)),
+        NamePolicy::Strict => sanitize_windows(
+            &name
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect::<String>(),
+        ),
+    }
+}
+
+fn sanitize_posix(name: &str) -> String {
     name.replace(
         ['/', '\\', '&', '?', '"', '\'', '*', '~', '|', ':', '<', '>'],
         "_",
     )
 }
 
+fn sanitize_windows(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| if c.is_control() { '_' } else { c })
+        .collect();
+
+    // windows silently drops trailing dots and spaces
+    let trimmed_len = name.trim_end_matches(['.', ' ']).len();
+    let trailing = name.len() - trimmed_len;
+    name.replace_range(trimmed_len.., &"_".repeat(trailing));
+
+    // reserved names are reserved with any extension too
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        name.insert(0, '_');
+    }
+
+    name
+}
+
+/// Lowercases a hex SHA-256 digest, or returns `None` if it isn't one.
+pub fn parse_sha256(value: &str) -> Option<String> {
+    let value = value.trim();
+    (value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit()))
+        .then(|| value.to_ascii_lowercase())
+}
+
+pub fn weak_etag(len: u64, modified: SystemTime) -> String {
+    let mtime = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    format!("W/\"{}-{}\"", len, mtime)
+}
+
+/// Whether an `If-None-Match` header matches an entity tag, using the weak
+/// comparison the spec asks for on GET and HEAD.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+/// Counts the bytes read through it and fails with
+/// [`io::ErrorKind::FileTooLarge`] once there are more than `limit`.
+pub struct LimitedReader<R> {
+    inner: R,
+    limit: u64,
+    read: u64,
+}
+
+impl<R> LimitedReader<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner,
+            limit,
+            read: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = result {
+            self.read += (buf.filled().len() - before) as u64;
+            if self.read > self.limit {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::FileTooLarge,
+                    "upload is above the size limit",
+                )));
+            }
+        }
+
+        result
+    }
+}
+
+const MIN_COPY_BUFFER: u64 = 8 * 1024;
+const MAX_COPY_BUFFER: u64 = 1024 * 1024;
+const DEFAULT_COPY_BUFFER: u64 = 64 * 1024;
+
+/// Scales the upload copy buffer with the declared size: big files need fewer
+/// syscalls, tiny ones shouldn't get a megabyte of buffer.
+pub fn copy_buffer_size(file_size: Option<u64>) -> usize {
+    file_size
+        .map(|file_size| (file_size / 64).clamp(MIN_COPY_BUFFER, MAX_COPY_BUFFER))
+        .unwrap_or(DEFAULT_COPY_BUFFER) as usize
+}
+
+/// Token bucket for accepting connections: `burst` connections may come in at
+/// once, after that they are let through at `rate` per second.
+pub struct AcceptLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl AcceptLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let burst = burst.max(1) as f64;
+        Self {
+            rate: rate as f64,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// Waits until another connection may be accepted.
+    pub async fn acquire(&mut self) {
+        self.refill();
+        if self.tokens < 1.0 {
+            sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.rate)).await;
+            self.refill();
+        }
+        self.tokens -= 1.0;
+    }
+}
+
+/// A `Content-Disposition` value offering `file_name`, with a plain ASCII
+/// fallback for clients that don't understand `filename*`.
+pub fn content_disposition(disposition: &str, file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| {
+            if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition,
+        fallback,
+        urlencoding::encode(file_name)
+    )
+}
+
 pub fn get_random_prefix(length: usize) -> String {
     rand::thread_rng()
         .sample_iter(rand::distributions::Alphanumeric)
@@ -46,6 +487,61 @@ pub fn get_ip(headers: &HeaderMap) -> Option<String> {
     }
 }
 
+pub fn favicon_content_type(path: &str) -> &'static str {
+    match path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+    {
+        Some(ext) if ext == "png" => "image/png",
+        Some(ext) if ext == "svg" => "image/svg+xml",
+        Some(ext) if ext == "gif" => "image/gif",
+        _ => "image/x-icon",
+    }
+}
+
+pub fn stats_cache_control(stats_interval: u64) -> String {
+    if stats_interval == 0 {
+        "no-cache".to_string()
+    } else {
+        format!("public, max-age={}", stats_interval)
+    }
+}
+
+/// Somewhere between `base` and twice that, so shed clients don't all come
+/// back at the same time.
+pub fn jittered_retry_after(base: u64) -> u64 {
+    base + rand::thread_rng().gen_range(0..=base)
+}
+
+/// Errors are always logged, successful requests only `sample_rate` of the time.
+pub fn should_log(status_code: u16, sample_rate: f64) -> bool {
+    status_code >= 400 || sample_rate >= 1.0 || rand::thread_rng().gen_bool(sample_rate.max(0.0))
+}
+
+/// Whether an error means there is no space left to write the upload, be it
+/// because of a quota or because the disk is full.
+pub fn is_storage_full(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::FilesystemQuotaExceeded | io::ErrorKind::StorageFull => true,
+        #[cfg(unix)]
+        _ if err.raw_os_error() == Some(ENOSPC) => true,
+        _ => false,
+    }
+}
+
+pub fn get_bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Compares two byte strings without returning early on the first mismatch.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 pub fn color_status_code(status_code: u16) -> Style {
     match status_code {
         100..=199 => Style::new().white(),
@@ -56,3 +552,166 @@ pub fn color_status_code(status_code: u16) -> Style {
         _ => Style::new(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("accept", HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn negotiates_format() {
+        assert_eq!(negotiate_format(&HeaderMap::new()), Format::Json);
+        assert_eq!(negotiate_format(&accept("*/*")), Format::Json);
+        assert_eq!(
+            negotiate_format(&accept("text/html,application/xhtml+xml;q=0.9,*/*;q=0.8")),
+            Format::Html
+        );
+        assert_eq!(negotiate_format(&accept("text/plain")), Format::Plain);
+        assert_eq!(
+            negotiate_format(&accept("text/html;q=0, text/plain;q=0.5")),
+            Format::Plain
+        );
+        assert_eq!(
+            negotiate_format(&accept("text/plain;q=0.5, application/json")),
+            Format::Json
+        );
+    }
+
+    #[test]
+    fn parses_sha256() {
+        let digest = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+        assert_eq!(
+            parse_sha256(&format!(" {} ", digest)),
+            Some(digest.to_ascii_lowercase())
+        );
+        assert_eq!(parse_sha256(&digest[1..]), None);
+        assert_eq!(parse_sha256(&digest.replace('E', "g")), None);
+    }
+
+    #[test]
+    fn detects_full_storage() {
+        assert!(is_storage_full(&io::Error::from(
+            io::ErrorKind::StorageFull
+        )));
+        assert!(is_storage_full(&io::Error::from(
+            io::ErrorKind::FilesystemQuotaExceeded
+        )));
+        #[cfg(unix)]
+        assert!(is_storage_full(&io::Error::from_raw_os_error(ENOSPC)));
+        assert!(!is_storage_full(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+    }
+
+    #[test]
+    fn sanitizes_file_names() {
+        assert_eq!(
+            sanitize_file_name("../etc/passwd", NamePolicy::Posix),
+            ".._etc_passwd"
+        );
+        assert_eq!(
+            sanitize_file_name("a<b>:c?.txt", NamePolicy::Posix),
+            "a_b__c_.txt"
+        );
+        assert_eq!(
+            sanitize_file_name("con.txt", NamePolicy::Windows),
+            "_con.txt"
+        );
+        assert_eq!(
+            sanitize_file_name("name. .", NamePolicy::Windows),
+            "name___"
+        );
+        assert_eq!(sanitize_file_name("a\tb", NamePolicy::Windows), "a_b");
+        assert_eq!(
+            sanitize_file_name("résumé (1).pdf", NamePolicy::Strict),
+            "r_sum___1_.pdf"
+        );
+        assert_eq!(sanitize_file_name("LPT1", NamePolicy::Strict), "_LPT1");
+    }
+
+    #[test]
+    fn matches_etags() {
+        let etag = weak_etag(42, UNIX_EPOCH + std::time::Duration::from_secs(7));
+        assert_eq!(etag, "W/\"42-7\"");
+        assert!(etag_matches("W/\"42-7\"", &etag));
+        assert!(etag_matches("\"42-7\"", &etag));
+        assert!(etag_matches("\"other\", W/\"42-7\"", &etag));
+        assert!(etag_matches("*", &etag));
+        assert!(!etag_matches("\"42-8\"", &etag));
+    }
+
+    #[test]
+    fn picks_encoding() {
+        let supported = ["br", "gzip"];
+        assert_eq!(preferred_encoding(None, &supported), None);
+        assert_eq!(
+            preferred_encoding(Some("gzip;q=0, identity"), &supported),
+            None
+        );
+        assert_eq!(preferred_encoding(Some("br, gzip"), &supported), Some("br"));
+        assert_eq!(preferred_encoding(Some("gzip, br"), &supported), Some("br"));
+        assert_eq!(preferred_encoding(Some("*"), &supported), Some("br"));
+        assert_eq!(
+            preferred_encoding(Some("br;q=0.5, gzip"), &supported),
+            Some("gzip")
+        );
+        assert_eq!(preferred_encoding(Some("x-gzip"), &supported), Some("gzip"));
+        assert_eq!(
+            preferred_encoding(Some("gzip;q=0.5, identity"), &supported),
+            None
+        );
+        assert_eq!(
+            preferred_encoding(Some("gzip, identity;q=0"), &supported),
+            Some("gzip")
+        );
+    }
+
+    #[test]
+    fn spots_double_extensions() {
+        let benign = ["tar.gz".to_string(), "min.js".to_string()];
+        assert!(has_suspicious_double_extension("a.jpg.exe", &benign));
+        assert!(has_suspicious_double_extension("a.pdf.EXE", &benign));
+        assert!(!has_suspicious_double_extension("a.exe", &benign));
+        assert!(!has_suspicious_double_extension(".bashrc.sh", &benign));
+        assert!(!has_suspicious_double_extension("app.min.js", &benign));
+        assert!(!has_suspicious_double_extension("a.jpg.png", &benign));
+        assert!(!has_suspicious_double_extension("a..exe", &benign));
+    }
+
+    #[test]
+    fn merges_vary() {
+        let mut headers = HeaderMap::new();
+        add_vary(&mut headers, "Accept");
+        add_vary(&mut headers, "accept");
+        add_vary(&mut headers, "Accept-Encoding");
+        assert_eq!(headers[VARY], "Accept, Accept-Encoding");
+
+        headers.insert(VARY, HeaderValue::from_static("*"));
+        add_vary(&mut headers, "Accept");
+        assert_eq!(headers[VARY], "*");
+    }
+
+    #[test]
+    fn caps_json_responses() {
+        let mut writer = CappedWriter {
+            buf: Vec::new(),
+            cap: 1024,
+        };
+        let err = serde_json::to_writer(&mut writer, &vec![u64::MAX; 10_000]).unwrap_err();
+        assert!(err.is_io());
+        // it stops growing at the cap instead of holding the whole thing
+        assert!(writer.buf.len() <= 1024);
+
+        let mut writer = CappedWriter {
+            buf: Vec::new(),
+            cap: 1024,
+        };
+        serde_json::to_writer(&mut writer, &[1, 2, 3]).unwrap();
+        assert_eq!(writer.buf, b"[1,2,3]");
+    }
+}
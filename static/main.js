@@ -47,7 +47,12 @@ function log(str, output = output) {
 }
 
 async function updateStats() {
-	let stats = await (await fetch("/stats")).json();
+	let stats = await (
+		await fetch("/stats", {
+			headers: { Accept: "application/json" },
+			cache: "no-cache",
+		})
+	).json();
 
 	maxFileSize = stats.max_file_size;
 
@@ -211,7 +216,7 @@ async function uploadFiles(files) {
 			continue;
 		}
 
-		if (res.status === 200) {
+		if (res.status === 200 || res.status === 201) {
 			let fileName = res.responseText;
 			let p = document.createElement("p");
 			p.append("uploaded ");